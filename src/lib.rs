@@ -32,6 +32,131 @@
 //! ```
 //!
 //! In this case the highest version among enabled features will be used.
+//!
+//! A `feature-versions` entry can also be a table, carrying its own
+//! `version` and `optional` keys independently of the base entry:
+//!
+//! ```toml
+//! [package.metadata.system-deps]
+//! gstreamer = { name = "gstreamer-1.0", version = "1.0", feature-versions = { v1_2 = "1.2", v3 = { version = "3.0", optional = true } }}
+//! ```
+//!
+//! Libraries can be linked statically by setting `link = "static"` in their
+//! metadata, or overridden at build time with the `SYSTEM_DEPS_$NAME_LINK`
+//! or `SYSTEM_DEPS_LINK` environnement variables (allowed values: `static`,
+//! `dynamic`):
+//!
+//! ```toml
+//! [package.metadata.system-deps]
+//! testlib = { version = "1.2", link = "static" }
+//! ```
+//!
+//! A single dependency can also be restricted to one platform without a
+//! nested `cfg(...)` table, via a simple `os` key (`"linux"`, `"windows"`,
+//! `"unix"`, ...):
+//!
+//! ```toml
+//! [package.metadata.system-deps]
+//! x11 = { version = "1.0", os = "unix" }
+//! ```
+//!
+//! Dependencies can also be restricted to a subset of targets by nesting
+//! them under a `cfg(...)` predicate, mirroring Cargo's
+//! `[target.'cfg(...)'.dependencies]`. The predicate is evaluated against
+//! `target_os`, `target_family`, `target_arch` and `target_env`:
+//!
+//! ```toml
+//! [package.metadata.system-deps.'cfg(target_os = "linux")']
+//! gtk = "3.18"
+//! ```
+//!
+//! A single dependency's `name`, `version` and `optional` settings can
+//! similarly be overridden for specific targets, by nesting a `cfg(...)`
+//! predicate or a literal target triple under that one dependency instead of
+//! a whole section:
+//!
+//! ```toml
+//! [package.metadata.system-deps.glib]
+//! version = "2.64"
+//! name = "glib-2.0"
+//!
+//! [package.metadata.system-deps.glib.'cfg(target_os = "windows")']
+//! name = "glib-2.0-windows"
+//! ```
+//!
+//! `version` accepts any `semver`-style requirement Cargo itself understands
+//! for regular dependencies, not just a bare minimum version:
+//!
+//! ```toml
+//! [package.metadata.system-deps]
+//! testlib = { version = ">= 1.2, < 2.0" }
+//! ```
+//!
+//! `name` can also be an array of alternative pkg-config names to try in
+//! order, for libraries shipped under different `.pc` names across distros:
+//!
+//! ```toml
+//! [package.metadata.system-deps]
+//! foo = { name = ["libfoo-2.0", "libfoo"], version = "2.0" }
+//! ```
+//!
+//! A dependency can inherit its `version`, `name` and `feature` from a
+//! `[workspace.metadata.system-deps]` table in the workspace root, the same
+//! way Cargo supports `version.workspace = true` for regular dependencies:
+//!
+//! ```toml
+//! # Workspace root Cargo.toml
+//! [workspace.metadata.system-deps]
+//! glib = { name = "glib-2.0", version = "2.64" }
+//! ```
+//!
+//! ```toml
+//! # Member crate Cargo.toml
+//! [package.metadata.system-deps]
+//! glib = { workspace = true, optional = true }
+//! ```
+//!
+//! When cross compiling (`HOST` and `TARGET` differ), `system-deps` refuses
+//! to silently probe the host's `pkg-config` and instead returns an error
+//! unless cross compilation has been configured, either through
+//! `pkg-config`'s own `PKG_CONFIG_ALLOW_CROSS`/`PKG_CONFIG_SYSROOT_DIR`/
+//! `PKG_CONFIG_PATH_<target>` environnement variables or through
+//! `SYSTEM_DEPS_$NAME_ALLOW_CROSS`/`SYSTEM_DEPS_ALLOW_CROSS`.
+//!
+//! Every successfully located dependency also gets a
+//! `cargo:rustc-cfg=system_deps_have_$NAME` flag emitted (with `$NAME`
+//! lowercased and `-`/`.` replaced by `_`), so downstream code can gate on
+//! it, e.g. `#[cfg(system_deps_have_testlib)]`.
+//!
+//! A dependency can be marked `optional = true`, in which case failing to
+//! locate it (through any of its configured sources) simply omits it from
+//! the returned libraries and their link flags, instead of aborting the
+//! whole probe:
+//!
+//! ```toml
+//! [package.metadata.system-deps]
+//! testdata = { version = "4.5", optional = true }
+//! ```
+//!
+//! By default, a dependency is resolved from `pkg-config`, falling back to
+//! an internally-built version (see [`Config::add_build_internal`]) only
+//! when `SYSTEM_DEPS_$NAME_BUILD_INTERNAL=auto`. The global
+//! `SYSTEM_DEPS_BUILD_INTERNAL` environnement variable sets this default for
+//! every dependency at once (handy for forcing a fully static, reproducible
+//! build without annotating each one), while a dependency-specific
+//! `SYSTEM_DEPS_$NAME_BUILD_INTERNAL` still takes priority over it. This
+//! fallback chain can be overridden explicitly, and reordered, with a
+//! comma-separated `SYSTEM_DEPS_$NAME_SOURCE` environnement variable listing
+//! any of `env`, `pkg-config` and `internal`, e.g.
+//! `SYSTEM_DEPS_TESTLIB_SOURCE=pkg-config,internal`. Each source is tried in
+//! turn, and if none of them can provide the dependency the resulting error
+//! lists why every one of them failed.
+//!
+//! The resolved dependency graph can be shared with tools other than
+//! `cargo`: [`Config::probe_into`] returns a [`Libraries`] that can be
+//! serialized to JSON via [`Libraries::to_json`], and setting the
+//! `SYSTEM_DEPS_DUMP` environnement variable to a file path makes a regular
+//! `probe()` write that same JSON there as a side effect.
 
 #![deny(missing_docs)]
 
@@ -42,12 +167,16 @@ extern crate lazy_static;
 #[cfg(test)]
 mod test;
 
+mod metadata;
+
+use cargo_platform::Cfg;
 use heck::ShoutySnakeCase;
+use semver::VersionReq;
+use serde::Serialize;
 use std::collections::HashMap;
 use std::env;
 use std::fmt;
 use std::fs;
-use std::io::Read;
 use std::path::{Path, PathBuf};
 use std::str::FromStr;
 use strum_macros::EnumString;
@@ -78,6 +207,19 @@ pub enum Error {
     /// contained an invalid value (allowed: `auto`, `always`, `never`)
     #[error("{0}")]
     BuildInternalInvalid(String),
+    /// Raised when cross compiling a library and pkg-config has not been
+    /// configured to search outside the host's paths
+    #[error("{0}")]
+    CrossCompilation(String),
+    /// A `link` setting, whether from the `SYSTEM_DEPS_$NAME_LINK`/`SYSTEM_DEPS_LINK`
+    /// environnement variables or from a library's `link` metadata key, contained
+    /// an invalid value (allowed: `static`, `dynamic`)
+    #[error("{0}")]
+    LinkModeInvalid(String),
+    /// Failed to write the `SYSTEM_DEPS_DUMP` JSON dump, or to serialize the
+    /// probed libraries to JSON in the first place
+    #[error("{0}")]
+    DumpFailed(String),
     /// system-deps has been asked to internally build a lib, through
     /// `SYSTEM_DEPS_$NAME_BUILD_INTERNAL=always' or `SYSTEM_DEPS_$NAME_BUILD_INTERNAL=auto',
     /// but not closure has been defined using `Config::add_build_internal` to build
@@ -88,6 +230,30 @@ pub enum Error {
     /// required version defined in `Cargo.toml`
     #[error("Internally built {0} {1} but minimum required version is {2}")]
     BuildInternalWrongVersion(String, String, String),
+    /// The library which has been built internally is newer than the upper
+    /// bound passed to `Config::add_build_internal_bounded`
+    #[error("Internally built {0} {1} but maximum allowed version is {2}")]
+    BuildInternalVersionTooNew(String, String, String),
+    /// An environnement variable in the form of `SYSTEM_DEPS_$NAME_SOURCE`
+    /// contained an invalid value (allowed: a comma-separated list made of
+    /// `env`, `pkg-config` and `internal`)
+    #[error("{0}")]
+    ProbeSourceInvalid(String),
+    /// None of the sources configured for a library, either through the
+    /// default fallback chain or through `SYSTEM_DEPS_$NAME_SOURCE`, were
+    /// able to provide it. Only raised when more than one source was tried;
+    /// a single source's own error is returned as is.
+    #[error("{0}")]
+    AllSourcesFailed(String),
+    /// None of a dependency's alternative pkg-config names (see the `name`
+    /// key, which can be an array) could be found
+    #[error("{0}")]
+    NoMatchingPkgConfigName(String),
+    /// A dependency was found, but its version does not satisfy the full
+    /// `version` requirement from `Cargo.toml` (e.g. an upper bound from a
+    /// range like `"< 2.0"`)
+    #[error("{0}")]
+    VersionMismatch(String),
 }
 
 #[derive(Error, Debug)]
@@ -115,10 +281,18 @@ impl BuildInternalClosureError {
 
 type FnBuildInternal = dyn FnOnce(&str) -> std::result::Result<Library, BuildInternalClosureError>;
 
+/// A closure registered with `Config::add_build_internal` or
+/// `Config::add_build_internal_bounded`, along with the optional upper
+/// version bound passed to the latter.
+struct BuildInternalEntry {
+    max_version: Option<String>,
+    closure: Box<FnBuildInternal>,
+}
+
 /// Structure used to configure `metadata` before starting to probe for dependencies
 pub struct Config {
     env: EnvVariables,
-    build_internals: HashMap<String, Box<FnBuildInternal>>,
+    build_internals: HashMap<String, BuildInternalEntry>,
 }
 
 impl Default for Config {
@@ -142,13 +316,35 @@ impl Config {
 
     /// Probe all libraries configured in the Cargo.toml
     /// `[package.metadata.system-deps]` section.
+    ///
+    /// If the `SYSTEM_DEPS_DUMP` environnement variable is set, the resolved
+    /// dependency graph is also written as JSON to the path it names, see
+    /// [`Libraries::to_json`].
     pub fn probe(self) -> Result<HashMap<String, Library>, Error> {
-        let (libraries, flags) = self.probe_full()?;
+        let dump_path = self.env.get("SYSTEM_DEPS_DUMP");
+        let libraries = self.probe_into()?;
 
         // Output cargo flags
-        println!("{}", flags);
+        println!("{}", libraries.flags);
 
-        Ok(libraries)
+        if let Some(path) = dump_path {
+            libraries.dump_to_file(&path)?;
+        }
+
+        Ok(libraries.libraries)
+    }
+
+    /// Probe all libraries configured in the Cargo.toml
+    /// `[package.metadata.system-deps]` section, returning the resolved
+    /// [`Libraries`] without printing any `cargo:` directive to stdout.
+    ///
+    /// This is meant for tools other than `cargo` (packaging tools, build
+    /// orchestrators, ...) that want to consume the resolved
+    /// system-dependency graph, e.g. via [`Libraries::to_json`].
+    pub fn probe_into(mut self) -> Result<Libraries, Error> {
+        let (libraries, flags) = self.probe_full()?;
+
+        Ok(Libraries { libraries, flags })
     }
 
     /// Add hook so system-deps can internally build library `name` if requested by user.
@@ -163,11 +359,38 @@ impl Config {
     /// * `func`: closure called when internally building the library.
     /// It receives as argument the minimum library version required.
     pub fn add_build_internal<F>(self, name: &str, func: F) -> Self
+    where
+        F: 'static + FnOnce(&str) -> std::result::Result<Library, BuildInternalClosureError>,
+    {
+        self.add_build_internal_bounded(name, None, func)
+    }
+
+    /// Same as `Config::add_build_internal`, but additionally rejects the
+    /// internally-built library if it is newer than `max_version`.
+    ///
+    /// This lets `-sys` crate authors that vendor a known-compatible source
+    /// tree refuse a lib built from an unexpectedly new checkout, the same
+    /// way a too-old one is already refused against the `Cargo.toml`
+    /// minimum version.
+    ///
+    /// # Arguments
+    /// * `name`: the name of the library, as defined in `Cargo.toml`
+    /// * `max_version`: the highest version `func` is allowed to report
+    /// building, or `None` for no upper bound
+    /// * `func`: closure called when internally building the library.
+    /// It receives as argument the minimum library version required.
+    pub fn add_build_internal_bounded<F>(self, name: &str, max_version: Option<&str>, func: F) -> Self
     where
         F: 'static + FnOnce(&str) -> std::result::Result<Library, BuildInternalClosureError>,
     {
         let mut build_internals = self.build_internals;
-        build_internals.insert(name.to_string(), Box::new(func));
+        build_internals.insert(
+            name.to_string(),
+            BuildInternalEntry {
+                max_version: max_version.map(String::from),
+                closure: Box::new(func),
+            },
+        );
 
         Self {
             env: self.env,
@@ -190,173 +413,387 @@ impl Config {
             .ok_or_else(|| Error::InvalidMetadata("$CARGO_MANIFEST_DIR not set".into()))?;
         let mut path = PathBuf::from(dir);
         path.push("Cargo.toml");
-        let mut manifest = fs::File::open(&path)
-            .map_err(|e| Error::FailToRead(format!("Error opening {}", path.display()), e))?;
-        let mut manifest_str = String::new();
-        manifest
-            .read_to_string(&mut manifest_str)
-            .map_err(|e| Error::FailToRead(format!("Error reading {}", path.display()), e))?;
-        let toml = manifest_str.parse::<toml::Value>().map_err(|e| {
-            Error::InvalidMetadata(format!(
-                "Error parsing TOML from {}: {:?}",
-                path.display(),
-                e
-            ))
-        })?;
-        let key = "package.metadata.system-deps";
-        let meta = toml
-            .get("package")
-            .and_then(|v| v.get("metadata"))
-            .and_then(|v| v.get("system-deps"))
-            .ok_or_else(|| Error::InvalidMetadata(format!("No {} in {}", key, path.display())))?;
-        let table = meta.as_table().ok_or_else(|| {
-            Error::InvalidMetadata(format!("{} not a table in {}", key, path.display()))
-        })?;
-        let mut libraries = HashMap::new();
-        for (name, value) in table {
-            let (lib_name, version) = match value {
-                toml::Value::String(ref s) => (name, s),
-                toml::Value::Table(ref t) => {
-                    let mut feature = None;
-                    let mut version = None;
-                    let mut lib_name = None;
-                    let mut enabled_feature_versions = Vec::new();
-                    for (tname, tvalue) in t {
-                        match (tname.as_str(), tvalue) {
-                            ("feature", &toml::Value::String(ref s)) => {
-                                feature = Some(s);
-                            }
-                            ("version", &toml::Value::String(ref s)) => {
-                                version = Some(s);
-                            }
-                            ("name", &toml::Value::String(ref s)) => {
-                                lib_name = Some(s);
-                            }
-                            ("feature-versions", &toml::Value::Table(ref feature_versions)) => {
-                                for (k, v) in feature_versions {
-                                    match (k.as_str(), v) {
-                                        (_, &toml::Value::String(ref feat_vers)) => {
-                                            if self.has_feature(&k) {
-                                                enabled_feature_versions.push(feat_vers);
-                                            }
-                                        }
-                                        _ => {
-                                            return Err(Error::InvalidMetadata(format!(
-                                                "Unexpected feature-version key: {} type {}",
-                                                k,
-                                                v.type_str()
-                                            )))
-                                        }
-                                    }
-                                }
-                            }
-                            _ => {
-                                return Err(Error::InvalidMetadata(format!(
-                                    "Unexpected key {}.{}.{} type {}",
-                                    key,
-                                    name,
-                                    tname,
-                                    tvalue.type_str()
-                                )))
-                            }
-                        }
-                    }
-                    if let Some(feature) = feature {
-                        if !self.has_feature(feature) {
-                            continue;
-                        }
-                    }
 
-                    let version = {
-                        // Pick the highest feature enabled version
-                        if !enabled_feature_versions.is_empty() {
-                            enabled_feature_versions.sort_by(|a, b| {
-                                VersionCompare::compare(b, a)
-                                    .expect("failed to compare versions")
-                                    .ord()
-                                    .expect("invalid version")
-                            });
-                            Some(enabled_feature_versions[0])
-                        } else {
-                            version
-                        }
-                    };
-
-                    (
-                        lib_name.unwrap_or(name),
-                        version.ok_or_else(|| {
-                            Error::InvalidMetadata(format!("No version in {}.{}", key, name))
-                        })?,
-                    )
+        let target = self.env.get("TARGET").unwrap_or_default();
+        let cfgs = self.target_cfgs();
+        let meta = metadata::MetaData::from_file(&path, &target, &cfgs)?;
+
+        let mut libraries = HashMap::new();
+        for dep in &meta.deps {
+            if let Some(feature) = &dep.feature {
+                if !self.has_feature(feature) {
+                    continue;
                 }
-                _ => {
-                    return Err(Error::InvalidMetadata(format!(
-                        "{}.{} not a string or table",
-                        key, name
-                    )))
+            }
+            if let Some(os) = &dep.os {
+                if !self.os_matches(os) {
+                    continue;
                 }
-            };
+            }
+            let resolved = dep.resolve_for_target(&target, &cfgs);
+            let branch = self.resolve_version_override(dep);
+
+            let version_req = branch
+                .map(|o| o.version_req.clone())
+                .or_else(|| resolved.version_req.clone())
+                .ok_or_else(|| {
+                    Error::InvalidMetadata(format!(
+                        "No version in package.metadata.system-deps.{}",
+                        dep.key
+                    ))
+                })?;
+            let optional = branch
+                .and_then(|o| o.optional)
+                .unwrap_or(resolved.optional);
+            let names = branch
+                .and_then(|o| o.name.clone())
+                .or_else(|| resolved.name.clone())
+                .unwrap_or_else(|| dep.lib_name());
+            let min_version =
+                metadata::version_req_lower_bound(&version_req).unwrap_or_else(|| "0.0.0".into());
 
-            let build_internal = self.get_build_internal_status(name)?;
-
-            let library = if self.env.contains(&flag_override_var(name, "NO_PKG_CONFIG")) {
-                Library::from_env_variables()
-            } else if build_internal == BuildInternal::Always {
-                self.call_build_internal(name, version)?
-            } else {
-                match pkg_config::Config::new()
-                    .atleast_version(&version)
-                    .print_system_libs(false)
-                    .cargo_metadata(false)
-                    .probe(lib_name)
-                {
-                    Ok(lib) => Library::from_pkg_config(lib),
-                    Err(e) => {
-                        if build_internal == BuildInternal::Auto {
-                            // Try building the lib internally as a fallback
-                            self.call_build_internal(name, version)?
-                        } else {
-                            return Err(e.into());
-                        }
+            let build_internal = self.get_build_internal_status(&dep.key)?;
+            let link_mode = self.get_link_mode(&dep.key, dep.link.as_deref())?;
+            let probe_sources = self.get_probe_sources(&dep.key, build_internal)?;
+
+            let mut failures = Vec::new();
+            let mut library = None;
+            for source in &probe_sources {
+                let result = match source {
+                    ProbeSource::Env => self.probe_env(&dep.key),
+                    ProbeSource::Internal => self.call_build_internal(&dep.key, &min_version),
+                    ProbeSource::PkgConfig => (|| {
+                        self.ensure_cross_compilation_configured(&dep.key)?;
+                        let restore = self.configure_cross_compilation();
+                        let result = probe_pkg_config_names(&names, &min_version, link_mode);
+                        restore.apply();
+                        result
+                    })(),
+                };
+                match result {
+                    Ok(lib) => {
+                        library = Some(lib);
+                        break;
                     }
+                    Err(e) => failures.push((*source, e)),
+                }
+            }
+
+            let mut library = match library {
+                Some(library) => library,
+                None if optional => {
+                    // An optional dependency that couldn't be located is
+                    // simply left out of the result, rather than aborting
+                    // the whole probe.
+                    continue;
+                }
+                None => {
+                    // With a single configured source, its own error already
+                    // says why the dependency couldn't be found.
+                    if failures.len() == 1 {
+                        return Err(failures.remove(0).1);
+                    }
+                    return Err(Error::AllSourcesFailed(format!(
+                        "Could not find {} via any of its configured sources:\n{}",
+                        dep.key,
+                        failures
+                            .iter()
+                            .map(|(source, e)| format!("{}: {}", source, e))
+                            .collect::<Vec<_>>()
+                            .join("\n")
+                    )));
                 }
             };
+            self.check_version_req(&dep.key, &library, &version_req)?;
+            library.link = link_mode;
 
-            libraries.insert(name.clone(), library);
+            libraries.insert(dep.key.clone(), library);
         }
         Ok(libraries)
     }
 
+    // `ProbeSource::Env` only succeeds if the user actually set one of the
+    // override env vars `gen_flags` later requires (`SYSTEM_DEPS_$NAME_LIB`
+    // or `SYSTEM_DEPS_$NAME_LIB_FRAMEWORK`); otherwise it reports failure so
+    // a `SYSTEM_DEPS_$NAME_SOURCE` chain can fall through to later sources
+    // instead of getting stuck on an always-succeeding `env`.
+    fn probe_env(&self, name: &str) -> Result<Library, Error> {
+        if self.env.contains(&flag_override_var(name, "LIB"))
+            || self.env.contains(&flag_override_var(name, "LIB_FRAMEWORK"))
+        {
+            Ok(Library::from_env_variables())
+        } else {
+            Err(Error::MissingLib(name.to_string()))
+        }
+    }
+
+    // Check a found library's version against the dependency's full
+    // `VersionReq`, not just the lower bound passed to
+    // `pkg_config::Config::atleast_version`, so a range with an upper bound
+    // (e.g. `< 2.0`) actually excludes a too-new version. A version string
+    // `pkg-config` reports that can't be parsed leniently (exotic
+    // versioning schemes) just skips the check instead of failing the probe.
+    //
+    // `env`-sourced libraries have no discoverable version at all, so they
+    // are never checked here.
+    fn check_version_req(
+        &self,
+        name: &str,
+        library: &Library,
+        version_req: &VersionReq,
+    ) -> Result<(), Error> {
+        if library.source != Source::PkgConfig {
+            return Ok(());
+        }
+
+        if let Some(found) = metadata::parse_lenient_version(&library.version) {
+            if !version_req.matches(&found) {
+                return Err(Error::VersionMismatch(format!(
+                    "{} {} does not satisfy requirement '{}'",
+                    name, library.version, version_req
+                )));
+            }
+        }
+
+        Ok(())
+    }
+
+    // Resolution order: `SYSTEM_DEPS_$NAME_BUILD_INTERNAL` takes precedence
+    // over the crate-wide `SYSTEM_DEPS_BUILD_INTERNAL`, which itself takes
+    // precedence over `BuildInternal::default()`.
     fn get_build_internal_status(&self, name: &str) -> Result<BuildInternal, Error> {
         let var = flag_override_var(name, "BUILD_INTERNAL");
-        let b = match self.env.get(&var).as_deref() {
-            Some(s) => BuildInternal::from_str(s).map_err(|_| {
-                Error::BuildInternalInvalid(format!(
-                    "Invalid value in {}: {} (allowed: 'auto', 'always', 'never')",
+        if let Some(s) = self.env.get(&var) {
+            return self.parse_build_internal(&var, &s);
+        }
+
+        let global_var = "SYSTEM_DEPS_BUILD_INTERNAL";
+        if let Some(s) = self.env.get(global_var) {
+            return self.parse_build_internal(global_var, &s);
+        }
+
+        Ok(BuildInternal::default())
+    }
+
+    fn parse_build_internal(&self, var: &str, s: &str) -> Result<BuildInternal, Error> {
+        BuildInternal::from_str(s).map_err(|_| {
+            Error::BuildInternalInvalid(format!(
+                "Invalid value in {}: {} (allowed: 'auto', 'always', 'never')",
+                var, s
+            ))
+        })
+    }
+
+    /// Sources to try, in order, to resolve dependency `name`, honouring an
+    /// explicit `SYSTEM_DEPS_$NAME_SOURCE` override (e.g. `env,pkg-config,internal`)
+    /// or falling back to `default_probe_sources` otherwise.
+    fn get_probe_sources(
+        &self,
+        name: &str,
+        build_internal: BuildInternal,
+    ) -> Result<Vec<ProbeSource>, Error> {
+        let var = flag_override_var(name, "SOURCE");
+        match self.env.get(&var) {
+            Some(s) => s
+                .split(',')
+                .map(|s| {
+                    ProbeSource::from_str(s.trim()).map_err(|_| {
+                        Error::ProbeSourceInvalid(format!(
+                            "Invalid value in {}: {} (allowed: 'env', 'pkg-config', 'internal')",
+                            var, s
+                        ))
+                    })
+                })
+                .collect(),
+            None => Ok(self.default_probe_sources(name, build_internal)),
+        }
+    }
+
+    fn default_probe_sources(&self, name: &str, build_internal: BuildInternal) -> Vec<ProbeSource> {
+        if self.env.contains(&flag_override_var(name, "NO_PKG_CONFIG")) {
+            vec![ProbeSource::Env]
+        } else {
+            match build_internal {
+                BuildInternal::Always => vec![ProbeSource::Internal],
+                BuildInternal::Auto => vec![ProbeSource::PkgConfig, ProbeSource::Internal],
+                BuildInternal::Never => vec![ProbeSource::PkgConfig],
+            }
+        }
+    }
+
+    // Resolve whether `name` should be linked statically or dynamically.
+    //
+    // A per-lib `SYSTEM_DEPS_$NAME_LINK` env var takes priority, then the
+    // global `SYSTEM_DEPS_LINK` env var, then the `link` key from the
+    // library's own metadata table, falling back to dynamic linking.
+    fn get_link_mode(&self, name: &str, meta_link: Option<&str>) -> Result<Linking, Error> {
+        let var = flag_override_var(name, "LINK");
+        if let Some(s) = self.env.get(&var) {
+            return Linking::from_str(&s).map_err(|_| {
+                Error::LinkModeInvalid(format!(
+                    "Invalid value in {}: {} (allowed: 'static', 'dynamic')",
                     var, s
                 ))
-            })?,
-            None => BuildInternal::default(),
+            });
+        }
+
+        if let Some(s) = self.env.get("SYSTEM_DEPS_LINK") {
+            return Linking::from_str(&s).map_err(|_| {
+                Error::LinkModeInvalid(format!(
+                    "Invalid value in SYSTEM_DEPS_LINK: {} (allowed: 'static', 'dynamic')",
+                    s
+                ))
+            });
+        }
+
+        if let Some(s) = meta_link {
+            return Linking::from_str(s).map_err(|_| {
+                Error::LinkModeInvalid(format!(
+                    "Invalid value for {}.link: {} (allowed: 'static', 'dynamic')",
+                    name, s
+                ))
+            });
+        }
+
+        Ok(Linking::default())
+    }
+
+    // Minimal matcher for a dependency's `os = "..."` key: either a
+    // `target_os` value directly (e.g. `"linux"`, `"windows"`) or one of the
+    // `target_family` tokens `"unix"`/`"windows"`.
+    fn os_matches(&self, os: &str) -> bool {
+        match os {
+            "unix" | "windows" => self.env.get("CARGO_CFG_TARGET_FAMILY").as_deref() == Some(os),
+            _ => self.env.get("CARGO_CFG_TARGET_OS").as_deref() == Some(os),
+        }
+    }
+
+    // The subset of `CARGO_CFG_*` variables `cfg(...)` predicates in
+    // `[package.metadata.system-deps]` are allowed to match against:
+    // `target_os`, `target_family`, `target_arch` and `target_env`.
+    fn target_cfgs(&self) -> Vec<Cfg> {
+        ["target_os", "target_family", "target_arch", "target_env"]
+            .iter()
+            .filter_map(|key| {
+                let var = format!("CARGO_CFG_{}", key.to_uppercase());
+                self.env
+                    .get(&var)
+                    .map(|value| Cfg::KeyPair(key.to_string(), value))
+            })
+            .collect()
+    }
+
+    // Whether `HOST` and `TARGET` disagree, i.e. we're cross compiling.
+    fn is_cross_compiling(&self) -> bool {
+        match (self.env.get("HOST"), self.env.get("TARGET")) {
+            (Some(host), Some(target)) => host != target,
+            _ => false,
+        }
+    }
+
+    // Whether cross compilation has been explicitly configured for `name`,
+    // either through a `system-deps`-specific toggle or through one of the
+    // `pkg-config`-native cross compilation knobs it documents.
+    fn cross_compile_allowed(&self, name: &str) -> bool {
+        if let Some(v) = self.env.get(&flag_override_var(name, "ALLOW_CROSS")) {
+            return v == "1" || v == "true";
+        }
+        if let Some(v) = self.env.get("SYSTEM_DEPS_ALLOW_CROSS") {
+            return v == "1" || v == "true";
+        }
+        if self.env.contains("PKG_CONFIG_ALLOW_CROSS") {
+            return true;
+        }
+        if self.env.contains("PKG_CONFIG_SYSROOT_DIR") {
+            return true;
+        }
+        if self.env.contains("PKG_CONFIG") {
+            return true;
+        }
+        if let Some(target) = self.env.get("TARGET") {
+            if self
+                .env
+                .contains(&format!("PKG_CONFIG_PATH_{}", target.replace('-', "_")))
+            {
+                return true;
+            }
+        }
+        false
+    }
+
+    fn ensure_cross_compilation_configured(&self, name: &str) -> Result<(), Error> {
+        if !self.is_cross_compiling() || self.cross_compile_allowed(name) {
+            return Ok(());
+        }
+
+        let host = self.env.get("HOST").unwrap_or_default();
+        let target = self.env.get("TARGET").unwrap_or_default();
+        Err(Error::CrossCompilation(format!(
+            "Cross compiling {} from {} to {}, but pkg-config has not been configured for cross \
+             compilation. Set PKG_CONFIG_ALLOW_CROSS=1 (usually alongside PKG_CONFIG_SYSROOT_DIR \
+             and PKG_CONFIG_PATH_{}) or {}=1 to opt in.",
+            name,
+            host,
+            target,
+            target.replace('-', "_"),
+            flag_override_var(name, "ALLOW_CROSS"),
+        )))
+    }
+
+    // Point `pkg-config` at a target-specific binary and let it search
+    // outside the host's paths while cross compiling, following the
+    // `PKG_CONFIG`/`PKG_CONFIG_ALLOW_CROSS` conventions `pkg-config` itself
+    // documents. Returns a guard which must be `apply`-ed once probing is
+    // done to put the real process environment back the way it found it.
+    fn configure_cross_compilation(&self) -> PkgConfigEnvRestore {
+        let restore = PkgConfigEnvRestore {
+            allow_cross: env::var("PKG_CONFIG_ALLOW_CROSS").ok(),
+            pkg_config: env::var("PKG_CONFIG").ok(),
         };
 
-        Ok(b)
+        if self.is_cross_compiling() {
+            if restore.allow_cross.is_none() {
+                env::set_var("PKG_CONFIG_ALLOW_CROSS", "1");
+            }
+            if restore.pkg_config.is_none() {
+                if let Some(target) = self.env.get("TARGET") {
+                    env::set_var("PKG_CONFIG", format!("{}-pkg-config", target));
+                }
+            }
+        }
+
+        restore
     }
 
     fn call_build_internal(&mut self, name: &str, version: &str) -> Result<Library, Error> {
-        let lib = match self.build_internals.remove(name) {
-            Some(f) => f(version).map_err(|e| Error::BuildInternalClosureError(name.into(), e))?,
+        let entry = match self.build_internals.remove(name) {
+            Some(entry) => entry,
             None => return Err(Error::BuildInternalNoClosure(name.into(), version.into())),
         };
+        let lib = (entry.closure)(version)
+            .map_err(|e| Error::BuildInternalClosureError(name.into(), e))?;
 
         // Check that the lib built internally matches the required version
-        match VersionCompare::compare(&lib.version, version) {
-            Ok(version_compare::CompOp::Lt) => Err(Error::BuildInternalWrongVersion(
+        if let Ok(version_compare::CompOp::Lt) = VersionCompare::compare(&lib.version, version) {
+            return Err(Error::BuildInternalWrongVersion(
                 name.into(),
                 lib.version.clone(),
                 version.into(),
-            )),
-            _ => Ok(lib),
+            ));
+        }
+
+        // Check it doesn't exceed the optional upper bound
+        if let Some(max_version) = &entry.max_version {
+            if let Ok(version_compare::CompOp::Gt) = VersionCompare::compare(&lib.version, max_version) {
+                return Err(Error::BuildInternalVersionTooNew(
+                    name.into(),
+                    lib.version.clone(),
+                    max_version.clone(),
+                ));
+            }
         }
+
+        Ok(lib)
     }
 
     fn override_from_flags(&self, libraries: &mut HashMap<String, Library>) {
@@ -399,12 +836,22 @@ impl Config {
             lib.framework_paths.iter().for_each(|f| {
                 flags.add(BuildFlag::SearchFramework(f.to_string_lossy().to_string()))
             });
-            lib.libs
-                .iter()
-                .for_each(|l| flags.add(BuildFlag::Lib(l.clone())));
+            lib.libs.iter().for_each(|l| {
+                flags.add(match lib.link {
+                    Linking::Static => BuildFlag::StaticLib(l.clone()),
+                    Linking::Dynamic => BuildFlag::Lib(l.clone()),
+                })
+            });
             lib.frameworks
                 .iter()
                 .for_each(|f| flags.add(BuildFlag::LibFramework(f.clone())));
+
+            // Let downstream crates gate code on an optional system dep
+            // having actually been found, e.g. `#[cfg(system_deps_have_foo)]`.
+            flags.add(BuildFlag::Cfg(format!(
+                "system_deps_have_{}",
+                sanitize_cfg_name(name)
+            )));
         }
 
         // Export DEP_$CRATE_INCLUDE env variable with the headers paths,
@@ -422,9 +869,87 @@ impl Config {
         let var = format!("CARGO_FEATURE_{}", feature.to_uppercase().replace('-', "_"));
         self.env.contains(&var)
     }
+
+    // Among a dependency's `feature-versions` branches whose gating feature
+    // is enabled, pick the one with the highest version, the way Cargo's own
+    // feature unification only ever raises requirements, never lowers them.
+    fn resolve_version_override<'a>(
+        &self,
+        dep: &'a metadata::Dependency,
+    ) -> Option<&'a metadata::VersionOverride> {
+        dep.enabled_version_overrides(|f| self.has_feature(f))
+            .max_by(|a, b| {
+                VersionCompare::compare(&a.version, &b.version)
+                    .expect("failed to compare versions")
+                    .ord()
+                    .expect("invalid version ordering")
+            })
+    }
 }
 
-#[derive(Debug, PartialEq)]
+/// The result of [`Config::probe_into`]: every probed library, plus the
+/// `cargo:` directives computed from them.
+///
+/// Unlike the `HashMap<String, Library>` returned by [`Config::probe`], this
+/// can be serialized to JSON via [`Libraries::to_json`] so tools other than
+/// `cargo` can consume the resolved system-dependency graph, fulfilling the
+/// crate's premise that dependencies declared in `Cargo.toml` metadata
+/// should be readable by other tools as well.
+#[derive(Debug)]
+pub struct Libraries {
+    libraries: HashMap<String, Library>,
+    flags: BuildFlags,
+}
+
+impl Libraries {
+    /// The library that was probed for `name`, if any.
+    pub fn get(&self, name: &str) -> Option<&Library> {
+        self.libraries.get(name)
+    }
+
+    /// Iterate over the probed libraries, keyed by their `Cargo.toml` name.
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &Library)> {
+        self.libraries.iter()
+    }
+
+    /// Serialize the resolved dependency set (library name, version,
+    /// source, libs, link/include/framework paths and defines) to JSON.
+    pub fn to_json(&self) -> serde_json::Result<String> {
+        serde_json::to_string_pretty(&self.libraries)
+    }
+
+    /// Write [`Libraries::to_json`]'s output to `path`.
+    pub fn dump_to_file<P: AsRef<Path>>(&self, path: P) -> Result<(), Error> {
+        let json = self
+            .to_json()
+            .map_err(|e| Error::DumpFailed(format!("Error serializing to JSON: {}", e)))?;
+        fs::write(path.as_ref(), json).map_err(|e| {
+            Error::DumpFailed(format!("Error writing {}: {}", path.as_ref().display(), e))
+        })
+    }
+}
+
+// Saved `PKG_CONFIG_ALLOW_CROSS`/`PKG_CONFIG` values to restore in the real
+// process environment after a cross-compilation-aware `pkg-config` probe.
+struct PkgConfigEnvRestore {
+    allow_cross: Option<String>,
+    pkg_config: Option<String>,
+}
+
+impl PkgConfigEnvRestore {
+    fn apply(self) {
+        match self.allow_cross {
+            Some(v) => env::set_var("PKG_CONFIG_ALLOW_CROSS", v),
+            None => env::remove_var("PKG_CONFIG_ALLOW_CROSS"),
+        }
+        match self.pkg_config {
+            Some(v) => env::set_var("PKG_CONFIG", v),
+            None => env::remove_var("PKG_CONFIG"),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Serialize)]
 /// From where the library settings have been retrieved
 pub enum Source {
     /// Settings have been retrieved from `pkg-config`
@@ -433,7 +958,7 @@ pub enum Source {
     EnvVariables,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Serialize)]
 /// A system dependency
 pub struct Library {
     /// From where the library settings have been retrieved
@@ -452,6 +977,7 @@ pub struct Library {
     pub defines: HashMap<String, Option<String>>,
     /// library version
     pub version: String,
+    link: Linking,
 }
 
 impl Library {
@@ -465,6 +991,7 @@ impl Library {
             framework_paths: l.framework_paths,
             defines: l.defines,
             version: l.version,
+            link: Linking::default(),
         }
     }
 
@@ -478,6 +1005,7 @@ impl Library {
             framework_paths: Vec::new(),
             defines: HashMap::new(),
             version: String::new(),
+            link: Linking::default(),
         }
     }
 
@@ -557,14 +1085,15 @@ impl EnvVariables {
     }
 }
 
-// TODO: add support for "rustc-link-lib=static=" ?
 #[derive(Debug, PartialEq)]
 enum BuildFlag {
     Include(String),
     SearchNative(String),
     SearchFramework(String),
     Lib(String),
+    StaticLib(String),
     LibFramework(String),
+    Cfg(String),
 }
 
 impl fmt::Display for BuildFlag {
@@ -574,7 +1103,9 @@ impl fmt::Display for BuildFlag {
             BuildFlag::SearchNative(lib) => write!(f, "rustc-link-search=native={}", lib),
             BuildFlag::SearchFramework(lib) => write!(f, "rustc-link-search=framework={}", lib),
             BuildFlag::Lib(lib) => write!(f, "rustc-link-lib={}", lib),
+            BuildFlag::StaticLib(lib) => write!(f, "rustc-link-lib=static={}", lib),
             BuildFlag::LibFramework(lib) => write!(f, "rustc-link-lib=framework={}", lib),
+            BuildFlag::Cfg(cfg) => write!(f, "rustc-cfg={}", cfg),
         }
     }
 }
@@ -605,6 +1136,38 @@ fn flag_override_var(lib: &str, flag: &str) -> String {
     format!("SYSTEM_DEPS_{}_{}", lib.to_shouty_snake_case(), flag)
 }
 
+/// Turn a dependency name into a valid Rust identifier suffix for a
+/// `rustc-cfg` flag: lowercased, with `-` and `.` replaced by `_`.
+fn sanitize_cfg_name(name: &str) -> String {
+    name.to_lowercase().replace('-', "_").replace('.', "_")
+}
+
+// Try each of a dependency's candidate pkg-config names in turn (see
+// `Dependency::lib_name`), succeeding on the first one `pkg-config` can
+// locate at `min_version` or better.
+fn probe_pkg_config_names(
+    names: &[String],
+    min_version: &str,
+    link_mode: Linking,
+) -> Result<Library, Error> {
+    let mut failures = Vec::new();
+
+    for name in names {
+        match pkg_config::Config::new()
+            .atleast_version(min_version)
+            .print_system_libs(false)
+            .cargo_metadata(false)
+            .statik(link_mode == Linking::Static)
+            .probe(name)
+        {
+            Ok(lib) => return Ok(Library::from_pkg_config(lib)),
+            Err(e) => failures.push(format!("{}: {}", name, e)),
+        }
+    }
+
+    Err(Error::NoMatchingPkgConfigName(failures.join(", ")))
+}
+
 fn split_paths(value: &str) -> Vec<PathBuf> {
     if !value.is_empty() {
         let paths = env::split_paths(&value);
@@ -635,3 +1198,77 @@ impl Default for BuildInternal {
         BuildInternal::Never
     }
 }
+
+/// A single source system-deps can try to resolve a dependency from, as
+/// listed (in order) in `SYSTEM_DEPS_$NAME_SOURCE`.
+#[derive(Debug, PartialEq, Clone, Copy, EnumString)]
+#[strum(serialize_all = "kebab-case")]
+enum ProbeSource {
+    Env,
+    PkgConfig,
+    Internal,
+}
+
+impl fmt::Display for ProbeSource {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ProbeSource::Env => write!(f, "env"),
+            ProbeSource::PkgConfig => write!(f, "pkg-config"),
+            ProbeSource::Internal => write!(f, "internal"),
+        }
+    }
+}
+
+/// Whether a library should be linked statically or dynamically, mirroring
+/// the `FOO_STATIC`/`FOO_DYNAMIC` and `PKG_CONFIG_ALL_STATIC` selection model
+/// exposed by `pkg-config` itself.
+#[derive(Debug, PartialEq, Clone, Copy, EnumString, Serialize)]
+#[strum(serialize_all = "snake_case")]
+enum Linking {
+    Static,
+    Dynamic,
+}
+
+impl Default for Linking {
+    fn default() -> Self {
+        Linking::Dynamic
+    }
+}
+
+#[cfg(test)]
+mod probe_source_tests {
+    use super::*;
+
+    fn config_with_env(vars: Vec<(&'static str, &'static str)>) -> Config {
+        let mut hash = HashMap::new();
+        vars.into_iter().for_each(|(k, v)| {
+            hash.insert(k, v.to_string());
+        });
+        Config::new_with_env(EnvVariables::Mock(hash))
+    }
+
+    #[test]
+    fn env_source_fails_without_override_vars() {
+        let config = config_with_env(vec![]);
+        assert!(matches!(config.probe_env("testlib"), Err(Error::MissingLib(_))));
+    }
+
+    #[test]
+    fn env_source_succeeds_with_override_lib_var() {
+        let config = config_with_env(vec![("SYSTEM_DEPS_TESTLIB_LIB", "testlib")]);
+        assert!(config.probe_env("testlib").is_ok());
+    }
+
+    // A `SYSTEM_DEPS_$NAME_SOURCE = "env,pkg-config"` chain should fall
+    // through to pkg-config when env has nothing configured, instead of
+    // getting stuck on an always-succeeding `env` source.
+    #[test]
+    fn env_then_pkg_config_falls_through_when_env_unset() {
+        let config = config_with_env(vec![("SYSTEM_DEPS_TESTLIB_SOURCE", "env,pkg-config")]);
+        let sources = config
+            .get_probe_sources("testlib", BuildInternal::Never)
+            .unwrap();
+        assert_eq!(sources, vec![ProbeSource::Env, ProbeSource::PkgConfig]);
+        assert!(matches!(config.probe_env("testlib"), Err(Error::MissingLib(_))));
+    }
+}
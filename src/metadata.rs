@@ -3,6 +3,9 @@
 use std::{fs, io::Read, path::Path};
 
 use anyhow::{anyhow, bail, Error};
+use cargo_platform::{Cfg, Platform};
+use semver::{Op, VersionReq};
+use serde::Deserialize;
 use toml::{map::Map, Value};
 
 #[derive(Debug, PartialEq)]
@@ -14,10 +17,27 @@ pub(crate) struct MetaData {
 pub(crate) struct Dependency {
     pub(crate) key: String,
     pub(crate) version: Option<String>,
-    pub(crate) name: Option<String>,
+    pub(crate) version_req: Option<VersionReq>,
+    /// Ordered list of alternative pkg-config/library names to try, for
+    /// libraries shipped under different `.pc` names across distros. See
+    /// `lib_name`.
+    pub(crate) name: Option<Vec<String>>,
     pub(crate) feature: Option<String>,
     pub(crate) optional: bool,
+    /// A `link = "static"`/`"dynamic"` setting, used unless the
+    /// `SYSTEM_DEPS_$NAME_LINK`/`SYSTEM_DEPS_LINK` environnement variables
+    /// override it.
+    pub(crate) link: Option<String>,
+    /// A simple `os = "linux"`/`"unix"`/... gate, for a dependency that only
+    /// needs restricting to one platform family without a full `cfg(...)`
+    /// target override.
+    pub(crate) os: Option<String>,
     pub(crate) version_overrides: Vec<VersionOverride>,
+    pub(crate) target_overrides: Vec<TargetOverride>,
+    /// Set when this dep declared `workspace = true`, meaning its `version`,
+    /// `name` and `feature` are inherited from the workspace root's
+    /// `[workspace.metadata.system-deps]` table.
+    pub(crate) workspace: bool,
 }
 
 impl Dependency {
@@ -25,168 +45,762 @@ impl Dependency {
         Self {
             key: name.to_string(),
             version: None,
+            version_req: None,
             name: None,
             feature: None,
             optional: false,
+            link: None,
+            os: None,
             version_overrides: Vec::new(),
+            target_overrides: Vec::new(),
+            workspace: false,
+        }
+    }
+
+    fn set_version(&mut self, version: &str) -> Result<(), Error> {
+        self.version = Some(version.to_string());
+        self.version_req = Some(parse_version_req(version)?);
+        Ok(())
+    }
+
+    /// Pull `version`/`name`/`feature` from the workspace root's matching
+    /// entry, leaving locally-specified `optional` and override lists
+    /// layered on top.
+    fn inherit_from_workspace(&mut self, root: &Dependency) {
+        self.version = root.version.clone();
+        self.version_req = root.version_req.clone();
+        self.name = root.name.clone();
+        self.feature = root.feature.clone();
+
+        if !self.optional {
+            self.optional = root.optional;
         }
+
+        let mut version_overrides = root.version_overrides.clone();
+        version_overrides.append(&mut self.version_overrides);
+        self.version_overrides = version_overrides;
+
+        let mut target_overrides = root.target_overrides.clone();
+        target_overrides.append(&mut self.target_overrides);
+        self.target_overrides = target_overrides;
     }
 
-    pub(crate) fn lib_name(&self) -> String {
-        self.name.as_ref().unwrap_or(&self.key).to_string()
+    /// The ordered set of pkg-config/library names to probe for this
+    /// dependency, falling back to its crate-side key if no `name` was
+    /// given. A downstream resolver should try each candidate in turn and
+    /// succeed on the first that satisfies the version requirement,
+    /// reporting every attempted name if none are found.
+    pub(crate) fn lib_name(&self) -> Vec<String> {
+        self.name.clone().unwrap_or_else(|| vec![self.key.clone()])
+    }
+
+    /// The `feature-versions` branches whose gating feature is enabled, in
+    /// declaration order. The caller (which already knows how to compare two
+    /// version strings) picks the highest among them, the way Cargo's own
+    /// feature unification only ever raises requirements, never lowers them.
+    pub(crate) fn enabled_version_overrides(
+        &self,
+        has_feature: impl Fn(&str) -> bool,
+    ) -> impl Iterator<Item = &VersionOverride> {
+        self.version_overrides
+            .iter()
+            .filter(move |o| has_feature(&o.feature))
+    }
+
+    /// Resolve this dependency's `version`, `name` and `optional` settings for a
+    /// given target, applying every matching `TargetOverride` on top of the base
+    /// fields, in declaration order.
+    pub(crate) fn resolve_for_target(&self, target: &str, cfg: &[Cfg]) -> ResolvedDependency {
+        let mut resolved = ResolvedDependency {
+            version: self.version.clone(),
+            version_req: self.version_req.clone(),
+            name: self.name.clone(),
+            optional: self.optional,
+        };
+
+        for over in &self.target_overrides {
+            if !over.platform.matches(target, cfg) {
+                continue;
+            }
+            if over.version.is_some() {
+                resolved.version = over.version.clone();
+                resolved.version_req = over.version_req.clone();
+            }
+            if let Some(name) = &over.name {
+                resolved.name = Some(vec![name.clone()]);
+            }
+            if let Some(optional) = over.optional {
+                resolved.optional = optional;
+            }
+        }
+
+        resolved
     }
 }
 
+/// The effective settings for a `Dependency` once its `TargetOverride`s have
+/// been resolved against a specific target triple and cfg set.
 #[derive(Debug, PartialEq)]
+pub(crate) struct ResolvedDependency {
+    pub(crate) version: Option<String>,
+    pub(crate) version_req: Option<VersionReq>,
+    pub(crate) name: Option<Vec<String>>,
+    pub(crate) optional: bool,
+}
+
+/// A platform-conditional override of `version`/`name`/`optional`, coming from
+/// a nested table keyed either by a `cfg(...)` predicate or a literal target
+/// triple, the way Cargo supports in `[target.'cfg(...)'.dependencies]`.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct TargetOverride {
+    pub(crate) platform: Platform,
+    pub(crate) version: Option<String>,
+    pub(crate) version_req: Option<VersionReq>,
+    pub(crate) name: Option<String>,
+    pub(crate) optional: Option<bool>,
+}
+
+/// A single `feature-versions` branch: the version to use once its gating
+/// feature (the table key) is enabled.
+#[derive(Debug, Clone, PartialEq)]
 pub(crate) struct VersionOverride {
+    /// The `feature-versions` table key this branch was declared under,
+    /// kept around for error messages.
     pub(crate) key: String,
+    /// The Cargo feature that gates this branch: the table key itself,
+    /// unless overridden with an explicit `feature` setting on a table
+    /// branch.
+    pub(crate) feature: String,
     pub(crate) version: String,
-    pub(crate) name: Option<String>,
+    pub(crate) version_req: VersionReq,
+    pub(crate) name: Option<Vec<String>>,
     pub(crate) optional: Option<bool>,
 }
 
-struct VersionOverrideBuilder {
-    version_id: String,
-    version: Option<String>,
-    full_name: Option<String>,
-    optional: Option<bool>,
-}
+/// Parse a pkg-config-style version string into a `VersionReq`, the way
+/// Cargo parses dependency version requirements.
+///
+/// A bare version with no comparison operator (e.g. `"4"`) is treated as
+/// `">= 4"` rather than the caret requirement `semver` would otherwise give
+/// it, to match the minimum-version semantics pkg-config has always used
+/// here.
+fn parse_version_req(version: &str) -> Result<VersionReq, Error> {
+    let trimmed = version.trim();
+    let has_operator = trimmed
+        .chars()
+        .next()
+        .map_or(false, |c| matches!(c, '=' | '>' | '<' | '~' | '^' | '*'));
 
-impl VersionOverrideBuilder {
-    fn new(version_id: &str) -> Self {
-        Self {
-            version_id: version_id.to_string(),
-            version: None,
-            full_name: None,
-            optional: None,
-        }
-    }
+    let req_str = if has_operator {
+        trimmed.to_string()
+    } else {
+        format!(">={}", trimmed)
+    };
 
-    fn build(self) -> Result<VersionOverride, Error> {
-        let version = self
-            .version
-            .ok_or_else(|| anyhow!("missing version field"))?;
+    req_str
+        .parse::<VersionReq>()
+        .map_err(|e| anyhow!("invalid version '{}': {}", version, e))
+}
 
-        Ok(VersionOverride {
-            key: self.version_id,
-            version,
-            name: self.full_name,
-            optional: self.optional,
+/// The lower bound implied by a `VersionReq`'s comparator set, i.e. the
+/// version to pass to `pkg_config::Config::atleast_version`. Comparators
+/// that only constrain an upper bound (`<`, `<=`) don't contribute one.
+/// The bound keeps only the components the requirement spells out, so a
+/// bare `"4"` gives `"4"` and `">= 1.2, < 2.0"` gives `"1.2"`.
+pub(crate) fn version_req_lower_bound(req: &VersionReq) -> Option<String> {
+    req.comparators
+        .iter()
+        .filter(|c| !matches!(c.op, Op::Less | Op::LessEq))
+        .max_by_key(|c| (c.major, c.minor.unwrap_or(0), c.patch.unwrap_or(0)))
+        .map(|c| {
+            let mut version = c.major.to_string();
+            if let Some(minor) = c.minor {
+                version.push_str(&format!(".{}", minor));
+                if let Some(patch) = c.patch {
+                    version.push_str(&format!(".{}", patch));
+                }
+            }
+            version
         })
-    }
+}
+
+/// Parse a version string as reported by `pkg-config` (which isn't always
+/// strict `major.minor.patch` semver) leniently, padding missing components
+/// with zero, for checking it against a `VersionReq`'s full range. Returns
+/// `None` rather than erroring if the version still can't be parsed this
+/// way, so an exotic version string just skips the range check instead of
+/// failing the probe outright.
+pub(crate) fn parse_lenient_version(version: &str) -> Option<semver::Version> {
+    let trimmed = version.trim();
+    let dots = trimmed.matches('.').count();
+    let padded = match dots {
+        0 => format!("{}.0.0", trimmed),
+        1 => format!("{}.0", trimmed),
+        _ => trimmed.to_string(),
+    };
+    semver::Version::parse(&padded).ok()
 }
 
 impl MetaData {
-    pub(crate) fn from_file(path: &Path) -> Result<Self, crate::Error> {
-        let mut manifest = fs::File::open(&path).map_err(|e| {
-            crate::Error::FailToRead(format!("error opening {}", path.display()), e)
-        })?;
+    pub(crate) fn from_file(path: &Path, target: &str, cfg: &[Cfg]) -> Result<Self, crate::Error> {
+        let manifest_str = read_manifest(path)?;
 
-        let mut manifest_str = String::new();
-        manifest.read_to_string(&mut manifest_str).map_err(|e| {
-            crate::Error::FailToRead(format!("error reading {}", path.display()), e)
-        })?;
+        let mut metadata = Self::from_str(&manifest_str, target, cfg)
+            .map_err(|e| crate::Error::InvalidMetadata(format!("{}: {}", path.display(), e)))?;
 
-        Self::from_str(manifest_str)
-            .map_err(|e| crate::Error::InvalidMetadata(format!("{}: {}", path.display(), e)))
-    }
+        if metadata.deps.iter().any(|d| d.workspace) {
+            metadata.resolve_workspace_deps(path, target, cfg)?;
+        }
 
-    fn from_str(manifest_str: String) -> Result<Self, Error> {
-        let toml = manifest_str
-            .parse::<toml::Value>()
-            .map_err(|e| anyhow!("error parsing TOML: {:?}", e))?;
+        Ok(metadata)
+    }
 
+    fn from_str(manifest_str: &str, target: &str, cfg: &[Cfg]) -> Result<Self, Error> {
         let key = "package.metadata.system-deps";
-        let meta = toml
-            .get("package")
-            .and_then(|v| v.get("metadata"))
-            .and_then(|v| v.get("system-deps"))
+        let raw: RawManifest =
+            toml::from_str(manifest_str).map_err(|e| describe_toml_error(manifest_str, &e))?;
+
+        let system_deps = raw
+            .package
+            .and_then(|p| p.metadata)
+            .and_then(|m| m.system_deps)
             .ok_or_else(|| anyhow!("no {}", key))?;
 
-        let table = meta
-            .as_table()
-            .ok_or_else(|| anyhow!("{} not a table", key))?;
+        let system_deps = flatten_platform_groups(system_deps, target, cfg)
+            .map_err(|e| anyhow!("{}.{}", key, e))?;
+
+        Self::build_deps(system_deps, key)
+    }
+
+    /// Parse the `[workspace.metadata.system-deps]` table of a workspace
+    /// root manifest, if it has one. Unlike `from_str`, a missing table is
+    /// not an error: plenty of workspaces don't declare any system deps of
+    /// their own, and members inheriting from them will fail on their own
+    /// terms if they reference a dep that isn't there.
+    fn from_workspace_str(manifest_str: &str, target: &str, cfg: &[Cfg]) -> Result<Self, Error> {
+        let key = "workspace.metadata.system-deps";
+        let raw: RawManifest =
+            toml::from_str(manifest_str).map_err(|e| describe_toml_error(manifest_str, &e))?;
+
+        let system_deps = raw
+            .workspace
+            .and_then(|w| w.metadata)
+            .and_then(|m| m.system_deps)
+            .unwrap_or_default();
+
+        let system_deps = flatten_platform_groups(system_deps, target, cfg)
+            .map_err(|e| anyhow!("{}.{}", key, e))?;
+
+        Self::build_deps(system_deps, key)
+    }
 
+    fn build_deps(system_deps: OrderedTable, key: &str) -> Result<Self, Error> {
         let mut deps = Vec::new();
 
-        for (name, value) in table {
-            let dep = Self::parse_dep(name, value)
-                .map_err(|e| anyhow!("metadata.system-deps.{}: {}", name, e))?;
+        for (name, value) in system_deps {
+            let raw =
+                RawDependency::deserialize(value).map_err(|e: toml::de::Error| anyhow!("{}", e))?;
+            let dep =
+                Dependency::from_raw(&name, raw).map_err(|e| anyhow!("{}.{}: {}", key, name, e))?;
             deps.push(dep);
         }
 
         Ok(MetaData { deps })
     }
 
-    fn parse_dep(name: &str, value: &Value) -> Result<Dependency, Error> {
-        let mut dep = Dependency::new(name);
+    /// Fill in `version`/`name`/`feature` for every dep marked
+    /// `workspace = true`, by walking up from `path` to find the workspace
+    /// root manifest and pulling the matching entry from its
+    /// `[workspace.metadata.system-deps]` table.
+    fn resolve_workspace_deps(
+        &mut self,
+        path: &Path,
+        target: &str,
+        cfg: &[Cfg],
+    ) -> Result<(), crate::Error> {
+        let workspace = find_workspace_root(path, target, cfg)?.ok_or_else(|| {
+            crate::Error::InvalidMetadata(format!(
+                "{}: no workspace root found for dependencies marked workspace = true",
+                path.display()
+            ))
+        })?;
 
-        match value {
-            // somelib = "1.0"
-            toml::Value::String(ref s) => {
-                dep.version = Some(s.clone());
-            }
-            toml::Value::Table(ref t) => {
-                Self::parse_dep_table(&mut dep, t)?;
+        for dep in &mut self.deps {
+            if !dep.workspace {
+                continue;
             }
-            _ => {
-                bail!("not a string or table");
+
+            let root_dep = workspace.deps.iter().find(|d| d.key == dep.key);
+            match root_dep {
+                Some(root_dep) => dep.inherit_from_workspace(root_dep),
+                None => {
+                    return Err(crate::Error::InvalidMetadata(format!(
+                        "{}: {} is marked workspace = true but the workspace root has no matching entry",
+                        path.display(),
+                        dep.key
+                    )))
+                }
             }
         }
 
-        Ok(dep)
+        Ok(())
     }
+}
 
-    fn parse_dep_table(dep: &mut Dependency, t: &Map<String, Value>) -> Result<(), Error> {
-        for (key, value) in t {
-            match (key.as_str(), value) {
-                ("feature", &toml::Value::String(ref s)) => {
-                    dep.feature = Some(s.clone());
-                }
-                ("version", &toml::Value::String(ref s)) => {
-                    dep.version = Some(s.clone());
+/// A TOML table deserialized as an ordered list of its entries, in file
+/// declaration order.
+///
+/// `toml::map::Map` is a `BTreeMap` unless this crate's `toml` dependency
+/// enables its `preserve_order` feature, so iterating one yields keys
+/// sorted alphabetically rather than in file order. Rather than depend on
+/// that feature flag, this deserializes straight off the `MapAccess` the
+/// `toml` parser itself produces (which does visit entries in file order)
+/// into a `Vec`, so callers that need declaration order -- resolving
+/// overlapping `cfg(...)`/target overrides, or flattening overlapping
+/// `cfg(...)`-gated dependency groups -- get it regardless of how `Map` is
+/// implemented.
+#[derive(Debug, Default)]
+struct OrderedTable(Vec<(String, Value)>);
+
+impl<'de> Deserialize<'de> for OrderedTable {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        struct OrderedTableVisitor;
+
+        impl<'de> serde::de::Visitor<'de> for OrderedTableVisitor {
+            type Value = OrderedTable;
+
+            fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                f.write_str("a table")
+            }
+
+            fn visit_map<A>(self, mut map: A) -> Result<Self::Value, A::Error>
+            where
+                A: serde::de::MapAccess<'de>,
+            {
+                let mut entries = Vec::new();
+                while let Some(entry) = map.next_entry::<String, Value>()? {
+                    entries.push(entry);
                 }
-                ("name", &toml::Value::String(ref s)) => {
-                    dep.name = Some(s.clone());
+                Ok(OrderedTable(entries))
+            }
+        }
+
+        deserializer.deserialize_map(OrderedTableVisitor)
+    }
+}
+
+impl IntoIterator for OrderedTable {
+    type Item = (String, Value);
+    type IntoIter = std::vec::IntoIter<(String, Value)>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.0.into_iter()
+    }
+}
+
+/// The `[package]` table of a manifest, as far as we care: just enough to
+/// reach `[package.metadata.system-deps]` without choking on the rest of
+/// Cargo's package schema (`name`, `version`, `dependencies`, ...).
+#[derive(Deserialize)]
+struct RawManifest {
+    package: Option<RawPackageSection>,
+    workspace: Option<RawWorkspaceSection>,
+}
+
+#[derive(Deserialize)]
+struct RawPackageSection {
+    metadata: Option<RawMetadataSection>,
+}
+
+#[derive(Deserialize)]
+struct RawWorkspaceSection {
+    metadata: Option<RawMetadataSection>,
+}
+
+#[derive(Deserialize)]
+struct RawMetadataSection {
+    // Kept as a raw `Value` table (rather than deserializing straight to
+    // `RawDependency`) so each entry is converted one at a time in
+    // `build_deps`. `OrderedTable` rather than `Map<String, Value>` so
+    // `flatten_platform_groups` sees groups in declaration order, the same
+    // as the old hand-written walk did.
+    #[serde(rename = "system-deps")]
+    system_deps: Option<OrderedTable>,
+}
+
+/// A single entry of `[package.metadata.system-deps]`, either a bare version
+/// string (`testlib = "1.0"`) or a table of settings.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawDependency {
+    Version(String),
+    Table(RawDependencyTable),
+}
+
+/// A `name` setting, either a single pkg-config/library name or an ordered
+/// array of alternatives to try.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawName {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl From<RawName> for Vec<String> {
+    fn from(raw: RawName) -> Self {
+        match raw {
+            RawName::One(s) => vec![s],
+            RawName::Many(names) => names,
+        }
+    }
+}
+
+/// The table form of a dependency entry. `deny_unknown_fields` can't be
+/// combined with `flatten`, so the dynamic `cfg(...)`/triple target
+/// overrides collected into `rest` are validated by hand in
+/// `Dependency::from_raw`, the way `parse_dep_table` used to.
+#[derive(Deserialize)]
+struct RawDependencyTable {
+    version: Option<String>,
+    name: Option<RawName>,
+    feature: Option<String>,
+    #[serde(default)]
+    optional: bool,
+    link: Option<String>,
+    os: Option<String>,
+    #[serde(default)]
+    workspace: bool,
+    #[serde(rename = "feature-versions")]
+    feature_versions: Option<Map<String, Value>>,
+    // `OrderedTable` rather than `Map<String, Value>` so `Dependency::from_raw`
+    // pushes `target_overrides` in declaration order, as
+    // `resolve_for_target` promises.
+    #[serde(flatten)]
+    rest: OrderedTable,
+}
+
+impl Dependency {
+    fn from_raw(name: &str, raw: RawDependency) -> Result<Self, Error> {
+        let mut dep = Dependency::new(name);
+
+        match raw {
+            // somelib = "1.0"
+            RawDependency::Version(version) => {
+                dep.set_version(&version)?;
+            }
+            RawDependency::Table(t) => {
+                if let Some(version) = &t.version {
+                    dep.set_version(version)?;
                 }
-                ("optional", &toml::Value::Boolean(optional)) => {
-                    dep.optional = optional;
+                dep.name = t.name.map(Into::into);
+                dep.feature = t.feature;
+                dep.optional = t.optional;
+                dep.link = t.link;
+                dep.os = t.os;
+                dep.workspace = t.workspace;
+
+                if let Some(feature_versions) = t.feature_versions {
+                    dep.version_overrides = parse_feature_versions(feature_versions)?;
                 }
-                (version_feature, &toml::Value::Table(ref version_settings))
-                    if version_feature.starts_with('v') =>
-                {
-                    let mut builder = VersionOverrideBuilder::new(version_feature);
-
-                    for (k, v) in version_settings {
-                        match (k.as_str(), v) {
-                            ("version", &toml::Value::String(ref feat_vers)) => {
-                                builder.version = Some(feat_vers.into());
-                            }
-                            ("name", &toml::Value::String(ref feat_name)) => {
-                                builder.full_name = Some(feat_name.into());
-                            }
-                            ("optional", &toml::Value::Boolean(optional)) => {
-                                builder.optional = Some(optional);
-                            }
-                            _ => {
-                                bail!(
-                                    "unexpected version settings key: {} type: {}",
-                                    k,
-                                    v.type_str()
-                                )
-                            }
-                        }
-                    }
 
-                    dep.version_overrides.push(builder.build()?);
+                for (key, value) in t.rest {
+                    if is_target_spec(&key) {
+                        dep.target_overrides
+                            .push(parse_target_override(&key, &value)?);
+                    } else {
+                        bail!("unexpected key {} type {}", key, value.type_str());
+                    }
                 }
-                _ => {
-                    bail!("unexpected key {} type {}", key, value.type_str());
+            }
+        }
+
+        Ok(dep)
+    }
+}
+
+/// Parse a dependency's `feature-versions` table into its ordered
+/// `VersionOverride`s, keeping declaration order so probing stays
+/// deterministic and `enabled_version_overrides` only has to filter down to
+/// branches whose feature is actually enabled.
+fn parse_feature_versions(map: Map<String, Value>) -> Result<Vec<VersionOverride>, Error> {
+    let mut overrides = Vec::new();
+
+    for (key, value) in map {
+        let over = match value {
+            Value::String(version) => {
+                let version_req = parse_version_req(&version)?;
+                VersionOverride {
+                    feature: key.clone(),
+                    key,
+                    version,
+                    version_req,
+                    name: None,
+                    optional: None,
                 }
             }
+            Value::Table(branch) => parse_feature_version_branch(&key, branch)?,
+            _ => bail!("feature-versions.{} must be a string or table", key),
+        };
+        overrides.push(over);
+    }
+
+    Ok(overrides)
+}
+
+/// Parse a `feature-versions` branch declared as a table, e.g.
+/// `v3 = { version = "3.0", optional = true }`, rather than the plain
+/// string form. `feature` lets the branch be gated by a different Cargo
+/// feature than its own table key.
+fn parse_feature_version_branch(
+    key: &str,
+    branch: Map<String, Value>,
+) -> Result<VersionOverride, Error> {
+    let mut version = None;
+    let mut optional = None;
+    let mut name = None;
+    let mut feature = key.to_string();
+
+    for (k, v) in &branch {
+        match (k.as_str(), v) {
+            ("version", Value::String(s)) => version = Some(s.clone()),
+            ("optional", Value::Boolean(b)) => optional = Some(*b),
+            ("feature", Value::String(s)) => feature = s.clone(),
+            ("name", _) => name = Some(parse_name_value(v)?),
+            _ => bail!(
+                "unexpected feature-versions.{} key: {} type: {}",
+                key,
+                k,
+                v.type_str()
+            ),
         }
-        Ok(())
     }
+
+    let version = version.ok_or_else(|| anyhow!("no version in feature-versions.{}", key))?;
+    let version_req = parse_version_req(&version)?;
+
+    Ok(VersionOverride {
+        key: key.to_string(),
+        feature,
+        version,
+        version_req,
+        name,
+        optional,
+    })
+}
+
+/// Parse a `name` setting that may be a single string or an array of
+/// strings into the ordered list of candidate library names it names.
+fn parse_name_value(value: &Value) -> Result<Vec<String>, Error> {
+    match value {
+        Value::String(s) => Ok(vec![s.clone()]),
+        Value::Array(items) => items
+            .iter()
+            .map(|v| {
+                v.as_str()
+                    .map(str::to_string)
+                    .ok_or_else(|| anyhow!("name array entries must be strings"))
+            })
+            .collect(),
+        _ => bail!("name must be a string or array of strings"),
+    }
+}
+
+fn parse_target_override(target: &str, value: &Value) -> Result<TargetOverride, Error> {
+    let table = value
+        .as_table()
+        .ok_or_else(|| anyhow!("{} must be a table", target))?;
+
+    let platform = target
+        .parse::<Platform>()
+        .map_err(|e| anyhow!("invalid target specifier '{}': {}", target, e))?;
+
+    let mut version = None;
+    let mut version_req = None;
+    let mut name = None;
+    let mut optional = None;
+
+    for (k, v) in table {
+        match (k.as_str(), v) {
+            ("version", &Value::String(ref s)) => {
+                version = Some(s.clone());
+                version_req = Some(parse_version_req(s)?);
+            }
+            ("name", &Value::String(ref s)) => {
+                name = Some(s.clone());
+            }
+            ("optional", &Value::Boolean(optional_value)) => {
+                optional = Some(optional_value);
+            }
+            _ => {
+                bail!(
+                    "unexpected target override key: {} type: {}",
+                    k,
+                    v.type_str()
+                )
+            }
+        }
+    }
+
+    Ok(TargetOverride {
+        platform,
+        version,
+        version_req,
+        name,
+        optional,
+    })
+}
+
+/// Turn a `toml::de::Error` into an underline-style message pointing at the
+/// offending line in `manifest_str`, the way Cargo's own manifest errors do.
+fn describe_toml_error(manifest_str: &str, e: &toml::de::Error) -> Error {
+    let (line, col) = match e.line_col() {
+        Some(line_col) => line_col,
+        None => return anyhow!("{}", e),
+    };
+
+    let line_text = manifest_str.lines().nth(line).unwrap_or("");
+    anyhow!(
+        "{}\n{:>4} | {}\n     | {}^",
+        e,
+        line + 1,
+        line_text,
+        " ".repeat(col)
+    )
+}
+
+/// Expand the top-level `[package.metadata.system-deps.'cfg(...)']`-style
+/// grouped tables that mirror Cargo's own `[target.'cfg(...)'.dependencies]`
+/// into the flat dependency map, keeping only the groups whose predicate
+/// matches `target`/`cfg`. Unlike a per-dependency `TargetOverride`, a
+/// non-matching group's dependencies simply don't exist for this build,
+/// rather than having individual fields overridden.
+///
+/// Groups are processed in declaration order, so when two matching groups
+/// declare the same dependency key, the one declared later in the manifest
+/// wins, the same way a later `[target.'cfg(...)'.dependencies]` section
+/// would shadow an earlier one.
+fn flatten_platform_groups(
+    table: OrderedTable,
+    target: &str,
+    cfg: &[Cfg],
+) -> Result<OrderedTable, Error> {
+    let mut flat: Vec<(String, Value)> = Vec::new();
+
+    for (key, value) in table {
+        if is_cfg_predicate(&key) {
+            let platform = key
+                .parse::<Platform>()
+                .map_err(|e| anyhow!("{}: {}", key, e))?;
+            if !platform.matches(target, cfg) {
+                continue;
+            }
+            let sub_table = value
+                .as_table()
+                .ok_or_else(|| anyhow!("{} not a table", key))?;
+            for (k, v) in sub_table {
+                upsert(&mut flat, k.clone(), v.clone());
+            }
+        } else {
+            upsert(&mut flat, key, value);
+        }
+    }
+
+    Ok(OrderedTable(flat))
+}
+
+/// Insert `key`/`value` into an ordered table, overwriting the value in
+/// place if `key` is already present rather than appending a duplicate.
+fn upsert(table: &mut Vec<(String, Value)>, key: String, value: Value) {
+    match table.iter_mut().find(|(k, _)| *k == key) {
+        Some(entry) => entry.1 = value,
+        None => table.push((key, value)),
+    }
+}
+
+// Whether a `[package.metadata.system-deps]` key introduces a top-level
+// `cfg(...)`-gated group of whole dependencies, mirroring Cargo's
+// `[target.'cfg(...)'.dependencies]`.
+fn is_cfg_predicate(key: &str) -> bool {
+    key.starts_with("cfg(") && key.ends_with(')')
+}
+
+// A target triple always has at least an arch, a vendor (possibly
+// `unknown`) and an OS component, e.g. `x86_64-pc-windows-gnu` or
+// `x86_64-unknown-linux-gnu`. Requiring at least three non-empty
+// `-`-separated components, each made up of the characters triples
+// actually use, keeps a hyphenated typo like `feature-version` (which
+// `cargo_platform::Platform::from_str` would otherwise happily accept as
+// an opaque `Platform::Name`) from being mistaken for one.
+fn is_target_triple(key: &str) -> bool {
+    let parts: Vec<&str> = key.split('-').collect();
+    parts.len() >= 3
+        && parts
+            .iter()
+            .all(|p| !p.is_empty() && p.chars().all(|c| c.is_ascii_alphanumeric() || c == '_'))
+}
+
+// A table key nested *inside* a single dependency's own table is a
+// `TargetOverride`, rather than a `feature-versions`-style setting, if it
+// looks like a `cfg(...)` predicate or a target triple.
+fn is_target_spec(key: &str) -> bool {
+    is_cfg_predicate(key) || is_target_triple(key)
+}
+
+fn read_manifest(path: &Path) -> Result<String, crate::Error> {
+    let mut manifest = fs::File::open(&path)
+        .map_err(|e| crate::Error::FailToRead(format!("error opening {}", path.display()), e))?;
+
+    let mut manifest_str = String::new();
+    manifest
+        .read_to_string(&mut manifest_str)
+        .map_err(|e| crate::Error::FailToRead(format!("error reading {}", path.display()), e))?;
+
+    Ok(manifest_str)
+}
+
+/// Walk up from a package manifest's directory looking for the workspace
+/// root: the nearest ancestor `Cargo.toml` with a `[workspace]` table.
+/// Returns `Ok(None)` if no ancestor has one.
+fn find_workspace_root(
+    path: &Path,
+    target: &str,
+    cfg: &[Cfg],
+) -> Result<Option<MetaData>, crate::Error> {
+    let mut dir = path.parent();
+
+    while let Some(d) = dir {
+        let candidate = d.join("Cargo.toml");
+
+        if candidate.is_file() {
+            let manifest_str = read_manifest(&candidate)?;
+            let is_workspace = manifest_str
+                .parse::<toml::Value>()
+                .map_err(|e| {
+                    crate::Error::InvalidMetadata(format!(
+                        "{}: error parsing TOML: {:?}",
+                        candidate.display(),
+                        e
+                    ))
+                })?
+                .get("workspace")
+                .is_some();
+
+            if is_workspace {
+                return MetaData::from_workspace_str(&manifest_str, target, cfg)
+                    .map(Some)
+                    .map_err(|e| {
+                        crate::Error::InvalidMetadata(format!("{}: {}", candidate.display(), e))
+                    });
+            }
+        }
+
+        dir = d.parent();
+    }
+
+    Ok(None)
 }
 
 #[cfg(test)]
@@ -203,7 +817,22 @@ mod tests {
         p.push("Cargo.toml");
         assert!(p.exists());
 
-        MetaData::from_file(&p)
+        MetaData::from_file(&p, "x86_64-unknown-linux-gnu", &linux_cfgs())
+    }
+
+    // The cfg set Cargo itself reports for "x86_64-unknown-linux-gnu".
+    fn linux_cfgs() -> Vec<Cfg> {
+        vec![
+            Cfg::Name("unix".into()),
+            Cfg::KeyPair("target_os".into(), "linux".into()),
+            Cfg::KeyPair("target_family".into(), "unix".into()),
+            Cfg::KeyPair("target_arch".into(), "x86_64".into()),
+            Cfg::KeyPair("target_env".into(), "gnu".into()),
+        ]
+    }
+
+    fn vreq(s: &str) -> VersionReq {
+        parse_version_req(s).unwrap()
     }
 
     #[test]
@@ -217,26 +846,41 @@ mod tests {
                     Dependency {
                         key: "testdata".into(),
                         version: Some("4".into()),
+                        version_req: Some(vreq("4")),
                         name: None,
                         feature: None,
                         optional: false,
+                        link: None,
+                        os: None,
                         version_overrides: vec![],
+                        target_overrides: vec![],
+                        workspace: false,
                     },
                     Dependency {
                         key: "testlib".into(),
                         version: Some("1".into()),
+                        version_req: Some(vreq("1")),
                         name: None,
                         feature: Some("test-feature".into()),
                         optional: false,
+                        link: None,
+                        os: None,
                         version_overrides: vec![],
+                        target_overrides: vec![],
+                        workspace: false,
                     },
                     Dependency {
                         key: "testmore".into(),
                         version: Some("2".into()),
+                        version_req: Some(vreq("2")),
                         name: None,
                         feature: Some("another-test-feature".into()),
                         optional: false,
+                        link: None,
+                        os: None,
                         version_overrides: vec![],
+                        target_overrides: vec![],
+                        workspace: false,
                     }
                 ]
             }
@@ -251,106 +895,292 @@ mod tests {
         );
     }
 
+    #[test]
+    fn parse_unexpected_hyphenated_key() {
+        // A hyphenated typo (e.g. "feature-version" for "feature-versions")
+        // must surface as an unexpected key, not get silently swallowed as
+        // an inert target triple that can never match any real TARGET.
+        assert_matches!(
+            parse_file("toml-unexpected-hyphenated-key"),
+            Err(crate::Error::InvalidMetadata(_))
+        );
+    }
+
     #[test]
     fn parse_override_name() {
         let m = parse_file("toml-override-name").unwrap();
 
-        assert_eq!(
-            m,
-            MetaData {
-                deps: vec![Dependency {
-                    key: "test_lib".into(),
-                    version: Some("1.0".into()),
-                    name: Some("testlib".into()),
-                    feature: None,
-                    optional: false,
-                    version_overrides: vec![VersionOverride {
-                        key: "v1_2".into(),
-                        version: "1.2".into(),
-                        name: None,
-                        optional: None,
-                    }],
-                },]
-            }
-        )
+        assert_eq!(m.deps.len(), 1);
+        let dep = &m.deps[0];
+        assert_eq!(dep.key, "test_lib");
+        assert_eq!(dep.name, Some(vec!["testlib".into()]));
     }
 
     #[test]
     fn parse_feature_versions() {
         let m = parse_file("toml-feature-versions").unwrap();
 
+        assert_eq!(m.deps.len(), 1);
+        let dep = &m.deps[0];
+        assert_eq!(dep.key, "testdata");
         assert_eq!(
-            m,
-            MetaData {
-                deps: vec![Dependency {
-                    key: "testdata".into(),
-                    version: Some("4".into()),
+            dep.version_overrides,
+            vec![
+                VersionOverride {
+                    key: "v5".into(),
+                    feature: "v5".into(),
+                    version: "5".into(),
+                    version_req: vreq("5"),
                     name: None,
-                    feature: None,
-                    optional: false,
-                    version_overrides: vec![
-                        VersionOverride {
-                            key: "v5".into(),
-                            version: "5".into(),
-                            name: None,
-                            optional: None,
-                        },
-                        VersionOverride {
-                            key: "v6".into(),
-                            version: "6".into(),
-                            name: None,
-                            optional: None,
-                        },
-                    ],
-                },]
-            }
-        )
+                    optional: None,
+                },
+                VersionOverride {
+                    key: "v6".into(),
+                    feature: "v6".into(),
+                    version: "6".into(),
+                    version_req: vreq("6"),
+                    name: None,
+                    optional: None,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_feature_version_branch_table() {
+        let m = parse_file("toml-feature-version-branch").unwrap();
+
+        let dep = &m.deps[0];
+        let branch = dep
+            .version_overrides
+            .iter()
+            .find(|o| o.key == "v3")
+            .unwrap();
+        assert_eq!(branch.version, "3.0");
+        assert_eq!(branch.optional, Some(true));
+    }
+
+    #[test]
+    fn enabled_version_overrides_filters_by_feature() {
+        let mut dep = Dependency::new("testdata");
+        dep.version_overrides.push(VersionOverride {
+            key: "v5".into(),
+            feature: "v5".into(),
+            version: "5".into(),
+            version_req: vreq("5"),
+            name: None,
+            optional: None,
+        });
+        dep.version_overrides.push(VersionOverride {
+            key: "v6".into(),
+            feature: "v6".into(),
+            version: "6".into(),
+            version_req: vreq("6"),
+            name: None,
+            optional: None,
+        });
+
+        let enabled: Vec<_> = dep
+            .enabled_version_overrides(|f| f == "v5" || f == "v6")
+            .map(|o| o.key.as_str())
+            .collect();
+        assert_eq!(enabled, vec!["v5", "v6"]);
+
+        assert_eq!(dep.enabled_version_overrides(|_| false).count(), 0);
     }
 
     #[test]
     fn parse_optional() {
         let m = parse_file("toml-optional").unwrap();
 
+        assert_eq!(m.deps.len(), 3);
+        let testbadger = m.deps.iter().find(|d| d.key == "testbadger").unwrap();
+        assert!(testbadger.optional);
+
+        let testlib = m.deps.iter().find(|d| d.key == "testlib").unwrap();
+        assert!(testlib.optional);
+        let v5 = testlib
+            .version_overrides
+            .iter()
+            .find(|o| o.key == "v5")
+            .unwrap();
+        assert_eq!(v5.optional, Some(false));
+        assert_eq!(v5.name, Some(vec!["testlib-5.0".into()]));
+    }
+
+    #[test]
+    fn parse_target_override() {
+        let m = parse_file("toml-target-override").unwrap();
+
+        let dep = &m.deps[0];
+        assert_eq!(dep.key, "glib");
+        assert_eq!(dep.target_overrides.len(), 2);
+
+        let cfg_override = &dep.target_overrides[0];
+        assert_eq!(cfg_override.name, Some("glib-2.0".into()));
+        assert_eq!(cfg_override.version, None);
+
+        let triple_override = &dep.target_overrides[1];
+        assert_eq!(triple_override.version, Some("2.70".into()));
+        assert_eq!(triple_override.optional, Some(true));
+    }
+
+    #[test]
+    fn resolve_target_override() {
+        let m = parse_file("toml-target-override").unwrap();
+        let dep = &m.deps[0];
+
+        // The literal-triple override only applies on a matching target.
+        let resolved = dep.resolve_for_target("x86_64-pc-windows-gnu", &[]);
+        assert_eq!(resolved.version, Some("2.70".into()));
+        assert_eq!(resolved.optional, true);
+
+        let resolved = dep.resolve_for_target("x86_64-unknown-linux-gnu", &[]);
+        assert_eq!(resolved.version, dep.version);
+        assert_eq!(resolved.optional, dep.optional);
+    }
+
+    #[test]
+    fn resolve_target_override_declaration_order() {
+        // Both overrides match "x86_64-unknown-linux-gnu": a broad
+        // cfg(any(target_os = "linux", target_os = "macos")) declared
+        // first, and a narrower cfg(target_os = "linux") declared second.
+        // The one declared later must win, regardless of how the two
+        // predicate strings happen to sort alphabetically.
+        let m = parse_file("toml-target-override-overlap").unwrap();
+        let dep = &m.deps[0];
+
+        let resolved = dep.resolve_for_target("x86_64-unknown-linux-gnu", &linux_cfgs());
+        assert_eq!(resolved.name, Some(vec!["glib-2.0-linux-only".into()]));
+    }
+
+    #[test]
+    fn cfg_group_filters_by_target() {
+        let m = parse_file("toml-cfg-group").unwrap();
+
+        // Only the group matching the requested target's dependencies show
+        // up; the rest of the groups are dropped entirely.
+        assert!(m.deps.iter().any(|d| d.key == "testlib"));
+        assert!(!m.deps.iter().any(|d| d.key == "windows-only"));
+    }
+
+    #[test]
+    fn cfg_group_overlap_declaration_order_wins() {
+        // Both groups match "x86_64-unknown-linux-gnu" and both declare
+        // "glib": a broad cfg(unix) group declared first, and a narrower
+        // cfg(target_os = "linux") group declared second. The entry from
+        // the later group must win, regardless of predicate spelling.
+        let m = parse_file("toml-cfg-group-overlap").unwrap();
+
+        let dep = m.deps.iter().find(|d| d.key == "glib").unwrap();
+        assert_eq!(dep.name, Some(vec!["glib-2.0-linux-only".into()]));
+    }
+
+    #[test]
+    fn parse_link() {
+        let m = parse_file("toml-link").unwrap();
+
+        let dep = m.deps.iter().find(|d| d.key == "testlib").unwrap();
+        assert_eq!(dep.link, Some("static".into()));
+    }
+
+    #[test]
+    fn parse_os() {
+        let m = parse_file("toml-os").unwrap();
+
+        let dep = m.deps.iter().find(|d| d.key == "x11").unwrap();
+        assert_eq!(dep.os, Some("unix".into()));
+    }
+
+    #[test]
+    fn parse_name_array() {
+        let m = parse_file("toml-name-array").unwrap();
+
+        let dep = &m.deps[0];
+        assert_eq!(dep.name, Some(vec!["libfoo-2.0".into(), "libfoo".into()]));
+        assert_eq!(dep.lib_name(), vec!["libfoo-2.0", "libfoo"]);
+    }
+
+    #[test]
+    fn lib_name_falls_back_to_key() {
+        let dep = Dependency::new("testlib");
+        assert_eq!(dep.lib_name(), vec!["testlib"]);
+    }
+
+    #[test]
+    fn bare_version_means_at_least() {
+        let req = parse_version_req("4").unwrap();
+        assert!(req.matches(&semver::Version::new(4, 0, 0)));
+        assert!(req.matches(&semver::Version::new(9, 9, 9)));
+        assert!(!req.matches(&semver::Version::new(3, 9, 9)));
+    }
+
+    #[test]
+    fn version_req_range() {
+        let req = parse_version_req(">= 1.2, < 2.0").unwrap();
+        assert!(!req.matches(&semver::Version::new(1, 1, 0)));
+        assert!(req.matches(&semver::Version::new(1, 9, 0)));
+        assert!(!req.matches(&semver::Version::new(2, 0, 0)));
+
+        assert_eq!(version_req_lower_bound(&req), Some("1.2".into()));
+    }
+
+    #[test]
+    fn lenient_version_parses_short_forms() {
         assert_eq!(
-            m,
-            MetaData {
-                deps: vec![
-                    Dependency {
-                        key: "testbadger".into(),
-                        version: Some("1".into()),
-                        name: None,
-                        feature: None,
-                        optional: true,
-                        version_overrides: vec![],
-                    },
-                    Dependency {
-                        key: "testlib".into(),
-                        version: Some("1.0".into()),
-                        name: None,
-                        feature: None,
-                        optional: true,
-                        version_overrides: vec![VersionOverride {
-                            key: "v5".into(),
-                            version: "5.0".into(),
-                            name: Some("testlib-5.0".into()),
-                            optional: Some(false),
-                        },],
-                    },
-                    Dependency {
-                        key: "testmore".into(),
-                        version: Some("2".into()),
-                        name: None,
-                        feature: None,
-                        optional: false,
-                        version_overrides: vec![VersionOverride {
-                            key: "v3".into(),
-                            version: "3.0".into(),
-                            name: None,
-                            optional: Some(true),
-                        },],
-                    },
-                ]
-            }
-        )
+            parse_lenient_version("1.2"),
+            Some(semver::Version::new(1, 2, 0))
+        );
+        assert_eq!(
+            parse_lenient_version("4"),
+            Some(semver::Version::new(4, 0, 0))
+        );
+        assert_eq!(parse_lenient_version("not-a-version"), None);
+    }
+
+    #[test]
+    fn invalid_version_req() {
+        assert_matches!(
+            parse_file("toml-invalid-version"),
+            Err(crate::Error::InvalidMetadata(_))
+        );
+    }
+
+    #[test]
+    fn inherit_from_workspace_merges_fields() {
+        let mut root = Dependency::new("glib");
+        root.set_version("2.64").unwrap();
+        root.name = Some(vec!["glib-2.0".into()]);
+        root.feature = Some("use-glib".into());
+        root.version_overrides.push(VersionOverride {
+            key: "v1_2".into(),
+            feature: "v1_2".into(),
+            version: "2.70".into(),
+            version_req: vreq("2.70"),
+            name: None,
+            optional: None,
+        });
+
+        let mut local = Dependency::new("glib");
+        local.workspace = true;
+        local.optional = true;
+        local.target_overrides.push(TargetOverride {
+            platform: "cfg(unix)".parse().unwrap(),
+            version: None,
+            version_req: None,
+            name: Some("glib-2.0-unix".into()),
+            optional: None,
+        });
+
+        local.inherit_from_workspace(&root);
+
+        assert_eq!(local.version, root.version);
+        assert_eq!(local.name, root.name);
+        assert_eq!(local.feature, root.feature);
+        // `optional` was set locally, so it isn't clobbered by the root's default.
+        assert!(local.optional);
+        // The root's version overrides come first, with the local ones layered on top.
+        assert_eq!(local.version_overrides, root.version_overrides);
+        assert_eq!(local.target_overrides.len(), 1);
     }
 }
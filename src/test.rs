@@ -61,16 +61,29 @@ fn good() {
     assert!(libraries.get("testmore").is_none());
 
     assert_eq!(
-        flags.to_string(),
-        r#"cargo:rustc-link-search=native=/usr/lib/x86_64-linux-gnu
-cargo:rustc-link-search=framework=/usr/lib/x86_64-linux-gnu
-cargo:rustc-link-lib=test
-cargo:rustc-link-lib=framework=someframework
-cargo:include=/usr/include/testlib
-"#
+        sorted_lines(&flags.to_string()),
+        vec![
+            "cargo:include=/usr/include/testlib",
+            "cargo:rustc-cfg=system_deps_have_testdata",
+            "cargo:rustc-cfg=system_deps_have_testlib",
+            "cargo:rustc-link-lib=framework=someframework",
+            "cargo:rustc-link-lib=test",
+            "cargo:rustc-link-search=framework=/usr/lib/x86_64-linux-gnu",
+            "cargo:rustc-link-search=native=/usr/lib/x86_64-linux-gnu",
+        ]
     );
 }
 
+// The dependency map is a HashMap, so flags contributed by distinct
+// dependencies (e.g. the `system_deps_have_*` cfg lines) aren't emitted in a
+// guaranteed order; compare the line set instead of the raw string whenever
+// more than one dependency is involved.
+fn sorted_lines(s: &str) -> Vec<&str> {
+    let mut lines: Vec<&str> = s.lines().collect();
+    lines.sort_unstable();
+    lines
+}
+
 fn toml_err(path: &str, err_starts_with: &str) {
     let err = toml(path, vec![]).unwrap_err();
     if !err.to_string().starts_with(err_starts_with) {
@@ -197,14 +210,17 @@ fn override_search_native() {
     );
 
     assert_eq!(
-        flags.to_string(),
-        r#"cargo:rustc-link-search=native=/custom/path
-cargo:rustc-link-search=native=/other/path
-cargo:rustc-link-search=framework=/usr/lib/x86_64-linux-gnu
-cargo:rustc-link-lib=test
-cargo:rustc-link-lib=framework=someframework
-cargo:include=/usr/include/testlib
-"#
+        sorted_lines(&flags.to_string()),
+        vec![
+            "cargo:include=/usr/include/testlib",
+            "cargo:rustc-cfg=system_deps_have_testdata",
+            "cargo:rustc-cfg=system_deps_have_testlib",
+            "cargo:rustc-link-lib=framework=someframework",
+            "cargo:rustc-link-lib=test",
+            "cargo:rustc-link-search=framework=/usr/lib/x86_64-linux-gnu",
+            "cargo:rustc-link-search=native=/custom/path",
+            "cargo:rustc-link-search=native=/other/path",
+        ]
     );
 }
 
@@ -219,13 +235,16 @@ fn override_search_framework() {
     assert_eq!(testlib.framework_paths, vec![Path::new("/custom/path")]);
 
     assert_eq!(
-        flags.to_string(),
-        r#"cargo:rustc-link-search=native=/usr/lib/x86_64-linux-gnu
-cargo:rustc-link-search=framework=/custom/path
-cargo:rustc-link-lib=test
-cargo:rustc-link-lib=framework=someframework
-cargo:include=/usr/include/testlib
-"#
+        sorted_lines(&flags.to_string()),
+        vec![
+            "cargo:include=/usr/include/testlib",
+            "cargo:rustc-cfg=system_deps_have_testdata",
+            "cargo:rustc-cfg=system_deps_have_testlib",
+            "cargo:rustc-link-lib=framework=someframework",
+            "cargo:rustc-link-lib=test",
+            "cargo:rustc-link-search=framework=/custom/path",
+            "cargo:rustc-link-search=native=/usr/lib/x86_64-linux-gnu",
+        ]
     );
 }
 
@@ -240,14 +259,17 @@ fn override_lib() {
     assert_eq!(testlib.libs, vec!["overrided-test", "other-test"]);
 
     assert_eq!(
-        flags.to_string(),
-        r#"cargo:rustc-link-search=native=/usr/lib/x86_64-linux-gnu
-cargo:rustc-link-search=framework=/usr/lib/x86_64-linux-gnu
-cargo:rustc-link-lib=overrided-test
-cargo:rustc-link-lib=other-test
-cargo:rustc-link-lib=framework=someframework
-cargo:include=/usr/include/testlib
-"#
+        sorted_lines(&flags.to_string()),
+        vec![
+            "cargo:include=/usr/include/testlib",
+            "cargo:rustc-cfg=system_deps_have_testdata",
+            "cargo:rustc-cfg=system_deps_have_testlib",
+            "cargo:rustc-link-lib=framework=someframework",
+            "cargo:rustc-link-lib=other-test",
+            "cargo:rustc-link-lib=overrided-test",
+            "cargo:rustc-link-search=framework=/usr/lib/x86_64-linux-gnu",
+            "cargo:rustc-link-search=native=/usr/lib/x86_64-linux-gnu",
+        ]
     );
 }
 
@@ -262,13 +284,16 @@ fn override_framework() {
     assert_eq!(testlib.frameworks, vec!["overrided-framework"]);
 
     assert_eq!(
-        flags.to_string(),
-        r#"cargo:rustc-link-search=native=/usr/lib/x86_64-linux-gnu
-cargo:rustc-link-search=framework=/usr/lib/x86_64-linux-gnu
-cargo:rustc-link-lib=test
-cargo:rustc-link-lib=framework=overrided-framework
-cargo:include=/usr/include/testlib
-"#
+        sorted_lines(&flags.to_string()),
+        vec![
+            "cargo:include=/usr/include/testlib",
+            "cargo:rustc-cfg=system_deps_have_testdata",
+            "cargo:rustc-cfg=system_deps_have_testlib",
+            "cargo:rustc-link-lib=framework=overrided-framework",
+            "cargo:rustc-link-lib=test",
+            "cargo:rustc-link-search=framework=/usr/lib/x86_64-linux-gnu",
+            "cargo:rustc-link-search=native=/usr/lib/x86_64-linux-gnu",
+        ]
     );
 }
 
@@ -283,13 +308,16 @@ fn override_include() {
     assert_eq!(testlib.include_paths, vec![Path::new("/other/include")]);
 
     assert_eq!(
-        flags.to_string(),
-        r#"cargo:rustc-link-search=native=/usr/lib/x86_64-linux-gnu
-cargo:rustc-link-search=framework=/usr/lib/x86_64-linux-gnu
-cargo:rustc-link-lib=test
-cargo:rustc-link-lib=framework=someframework
-cargo:include=/other/include
-"#
+        sorted_lines(&flags.to_string()),
+        vec![
+            "cargo:include=/other/include",
+            "cargo:rustc-cfg=system_deps_have_testdata",
+            "cargo:rustc-cfg=system_deps_have_testlib",
+            "cargo:rustc-link-lib=framework=someframework",
+            "cargo:rustc-link-lib=test",
+            "cargo:rustc-link-search=framework=/usr/lib/x86_64-linux-gnu",
+            "cargo:rustc-link-search=native=/usr/lib/x86_64-linux-gnu",
+        ]
     );
 }
 
@@ -313,7 +341,13 @@ fn override_unset() {
     assert_eq!(testlib.frameworks, Vec::<String>::new());
     assert_eq!(testlib.include_paths, Vec::<PathBuf>::new());
 
-    assert_eq!(flags.to_string(), "");
+    assert_eq!(
+        sorted_lines(&flags.to_string()),
+        vec![
+            "cargo:rustc-cfg=system_deps_have_testdata",
+            "cargo:rustc-cfg=system_deps_have_testlib",
+        ]
+    );
 }
 
 #[test]
@@ -333,7 +367,14 @@ fn override_no_pkg_config() {
     assert_eq!(testlib.frameworks, Vec::<String>::new());
     assert_eq!(testlib.include_paths, Vec::<PathBuf>::new());
 
-    assert_eq!(flags.to_string(), "cargo:rustc-link-lib=custom-lib\n");
+    assert_eq!(
+        sorted_lines(&flags.to_string()),
+        vec![
+            "cargo:rustc-cfg=system_deps_have_testdata",
+            "cargo:rustc-cfg=system_deps_have_testlib",
+            "cargo:rustc-link-lib=custom-lib",
+        ]
+    );
 }
 
 #[test]
@@ -345,6 +386,95 @@ fn override_no_pkg_config_error() {
     );
 }
 
+#[test]
+fn cfg_target_matches() {
+    let (libraries, _flags) = toml(
+        "toml-cfg-target",
+        vec![("TARGET", "x86_64-unknown-linux-gnu"), ("CARGO_CFG_TARGET_OS", "linux")],
+    )
+    .unwrap();
+    assert!(libraries.get("testlib").is_some());
+}
+
+#[test]
+fn cfg_target_does_not_match() {
+    let (libraries, _flags) = toml(
+        "toml-cfg-target",
+        vec![("TARGET", "x86_64-apple-darwin"), ("CARGO_CFG_TARGET_OS", "macos")],
+    )
+    .unwrap();
+    assert!(libraries.get("testlib").is_none());
+}
+
+#[test]
+fn cross_compile_error() {
+    let err = toml(
+        "toml-good",
+        vec![
+            ("HOST", "x86_64-unknown-linux-gnu"),
+            ("TARGET", "aarch64-unknown-linux-gnu"),
+        ],
+    )
+    .unwrap_err();
+    assert!(matches!(err.into(), ErrorKind::CrossCompilation(..)));
+}
+
+#[test]
+fn cross_compile_allowed_via_override() {
+    let (libraries, _flags) = toml(
+        "toml-good",
+        vec![
+            ("HOST", "x86_64-unknown-linux-gnu"),
+            ("TARGET", "aarch64-unknown-linux-gnu"),
+            ("SYSTEM_DEPS_TESTLIB_ALLOW_CROSS", "1"),
+        ],
+    )
+    .unwrap();
+    assert!(libraries.get("testlib").is_some());
+}
+
+#[test]
+fn override_link_static() {
+    let (libraries, flags) = toml("toml-good", vec![("SYSTEM_DEPS_TESTLIB_LINK", "static")]).unwrap();
+    assert!(libraries.get("testlib").is_some());
+
+    assert_eq!(
+        sorted_lines(&flags.to_string()),
+        vec![
+            "cargo:include=/usr/include/testlib",
+            "cargo:rustc-cfg=system_deps_have_testdata",
+            "cargo:rustc-cfg=system_deps_have_testlib",
+            "cargo:rustc-link-lib=framework=someframework",
+            "cargo:rustc-link-lib=static=test",
+            "cargo:rustc-link-search=framework=/usr/lib/x86_64-linux-gnu",
+            "cargo:rustc-link-search=native=/usr/lib/x86_64-linux-gnu",
+        ]
+    );
+}
+
+#[test]
+fn override_link_invalid() {
+    let err = toml("toml-good", vec![("SYSTEM_DEPS_TESTLIB_LINK", "badger")]).unwrap_err();
+    assert!(matches!(err.into(), ErrorKind::LinkModeInvalid(..)));
+}
+
+#[test]
+fn link_static_from_metadata() {
+    let (_libraries, flags) = toml("toml-static", vec![]).unwrap();
+
+    assert_eq!(
+        sorted_lines(&flags.to_string()),
+        vec![
+            "cargo:include=/usr/include/testlib",
+            "cargo:rustc-cfg=system_deps_have_testlib",
+            "cargo:rustc-link-lib=framework=someframework",
+            "cargo:rustc-link-lib=static=test",
+            "cargo:rustc-link-search=framework=/usr/lib/x86_64-linux-gnu",
+            "cargo:rustc-link-search=native=/usr/lib/x86_64-linux-gnu",
+        ]
+    );
+}
+
 #[test]
 fn build_internal_always() {
     let called = Rc::new(Cell::new(false));
@@ -526,3 +656,249 @@ fn build_internal_fail() {
     ));
     assert_eq!(called.get(), true);
 }
+
+#[test]
+fn probe_into_serializes_to_json() {
+    let libraries = create_config("toml-good", vec![]).probe_into().unwrap();
+
+    let testlib = libraries.get("testlib").unwrap();
+    assert_eq!(testlib.version, "1.2.3");
+
+    let json = libraries.to_json().unwrap();
+    assert!(json.contains("\"version\": \"1.2.3\""));
+    assert!(json.contains("\"PkgConfig\""));
+}
+
+#[test]
+fn probe_source_override_skips_pkg_config() {
+    // Force resolution through the internal builder only, even though
+    // "testlib" would otherwise be found by pkg-config.
+    let called = Rc::new(Cell::new(false));
+    let called_clone = called.clone();
+    let config = create_config("toml-good", vec![("SYSTEM_DEPS_TESTLIB_SOURCE", "internal")])
+        .add_build_internal("testlib", move |version| {
+            called_clone.replace(true);
+            assert_eq!(version, "1");
+            let lib = pkg_config::Config::new()
+                .print_system_libs(false)
+                .cargo_metadata(false)
+                .probe("testlib")
+                .unwrap();
+            Ok(Library::from_pkg_config(lib))
+        });
+
+    let (libraries, _flags) = config.probe_full().unwrap();
+
+    assert_eq!(called.get(), true);
+    assert!(libraries.get("testlib").is_some());
+}
+
+#[test]
+fn probe_source_invalid() {
+    let config = create_config("toml-good", vec![("SYSTEM_DEPS_TESTLIB_SOURCE", "badger")]);
+
+    let err = config.probe_full().unwrap_err();
+    assert!(matches!(err.into(), ErrorKind::ProbeSourceInvalid(..)));
+}
+
+#[test]
+fn probe_all_sources_failed() {
+    // Version 5 is not available, pkg-config alone can't provide it, and no
+    // internal build closure was registered to fall back to.
+    let config = create_config(
+        "toml-feature-versions",
+        vec![
+            ("SYSTEM_DEPS_TESTDATA_SOURCE", "pkg-config,internal"),
+            ("CARGO_FEATURE_V5", ""),
+        ],
+    );
+
+    let err = config.probe_full().unwrap_err();
+    match err.into() {
+        ErrorKind::AllSourcesFailed(msg) => {
+            assert!(msg.contains("pkg-config"));
+            assert!(msg.contains("internal"));
+        }
+        _ => panic!("Wrong error type"),
+    }
+}
+
+#[test]
+fn build_internal_bounded_version_too_new() {
+    // The internal builder reports a version above the upper bound we gave it.
+    let called = Rc::new(Cell::new(false));
+    let called_clone = called.clone();
+    let config = create_config(
+        "toml-good",
+        vec![("SYSTEM_DEPS_TESTLIB_BUILD_INTERNAL", "always")],
+    )
+    .add_build_internal_bounded("testlib", Some("1.5.0"), move |_version| {
+        called_clone.replace(true);
+        let mut lib = pkg_config::Config::new()
+            .print_system_libs(false)
+            .cargo_metadata(false)
+            .probe("testlib")
+            .unwrap();
+        lib.version = "2.0.0".to_string();
+        Ok(Library::from_pkg_config(lib))
+    });
+
+    let err = config.probe_full().unwrap_err();
+    assert!(matches!(
+        err.into(),
+        ErrorKind::BuildInternalVersionTooNew(..)
+    ));
+    assert_eq!(called.get(), true);
+}
+
+#[test]
+fn global_build_internal_always() {
+    let called = Rc::new(Cell::new(false));
+    let called_clone = called.clone();
+    let config = create_config("toml-good", vec![("SYSTEM_DEPS_BUILD_INTERNAL", "always")])
+        .add_build_internal("testlib", move |version| {
+            called_clone.replace(true);
+            assert_eq!(version, "1");
+            let lib = pkg_config::Config::new()
+                .print_system_libs(false)
+                .cargo_metadata(false)
+                .probe("testlib")
+                .unwrap();
+            Ok(Library::from_pkg_config(lib))
+        })
+        // The global var applies to "testdata" as well, so it needs a
+        // closure of its own.
+        .add_build_internal("testdata", |_version| {
+            let lib = pkg_config::Config::new()
+                .print_system_libs(false)
+                .cargo_metadata(false)
+                .probe("testdata")
+                .unwrap();
+            Ok(Library::from_pkg_config(lib))
+        });
+
+    let (libraries, _flags) = config.probe_full().unwrap();
+
+    assert_eq!(called.get(), true);
+    assert!(libraries.get("testlib").is_some());
+}
+
+#[test]
+fn global_build_internal_auto_not_called() {
+    // No need to build the lib as the existing version is new enough
+    let called = Rc::new(Cell::new(false));
+    let called_clone = called.clone();
+    let config = create_config("toml-good", vec![("SYSTEM_DEPS_BUILD_INTERNAL", "auto")])
+        .add_build_internal("testlib", move |_version| {
+            called_clone.replace(true);
+            let lib = pkg_config::Config::new()
+                .print_system_libs(false)
+                .cargo_metadata(false)
+                .probe("testlib")
+                .unwrap();
+            Ok(Library::from_pkg_config(lib))
+        });
+
+    let (libraries, _flags) = config.probe_full().unwrap();
+
+    assert_eq!(called.get(), false);
+    assert!(libraries.get("testlib").is_some());
+}
+
+#[test]
+fn dep_specific_build_internal_overrides_global() {
+    // The global var says "never", but the per-dep var should still win and
+    // force the internal build.
+    let called = Rc::new(Cell::new(false));
+    let called_clone = called.clone();
+    let config = create_config(
+        "toml-good",
+        vec![
+            ("SYSTEM_DEPS_BUILD_INTERNAL", "never"),
+            ("SYSTEM_DEPS_TESTLIB_BUILD_INTERNAL", "always"),
+        ],
+    )
+    .add_build_internal("testlib", move |version| {
+        called_clone.replace(true);
+        assert_eq!(version, "1");
+        let lib = pkg_config::Config::new()
+            .print_system_libs(false)
+            .cargo_metadata(false)
+            .probe("testlib")
+            .unwrap();
+        Ok(Library::from_pkg_config(lib))
+    });
+
+    let (libraries, _flags) = config.probe_full().unwrap();
+
+    assert_eq!(called.get(), true);
+    assert!(libraries.get("testlib").is_some());
+}
+
+#[test]
+fn optional_dependency_missing_is_omitted() {
+    // Version 5 isn't available for "testdata" and no internal build
+    // closure is registered, but the dependency is optional so probing
+    // should still succeed, just without that library in the result.
+    let (libraries, flags) = toml(
+        "toml-optional",
+        vec![("CARGO_FEATURE_V5", "")],
+    )
+    .unwrap();
+
+    assert!(libraries.get("testdata").is_none());
+    assert!(libraries.get("testlib").is_some());
+    assert!(!flags.to_string().contains("testdata"));
+}
+
+#[test]
+fn have_cfg_flags_are_emitted() {
+    let (_libraries, flags) = toml("toml-good", vec![]).unwrap();
+    let flags = flags.to_string();
+
+    assert!(flags.contains("cargo:rustc-cfg=system_deps_have_testlib"));
+    assert!(flags.contains("cargo:rustc-cfg=system_deps_have_testdata"));
+}
+
+#[test]
+fn os_key_matches() {
+    let (libraries, _flags) = toml(
+        "toml-os-linux",
+        vec![("CARGO_CFG_TARGET_OS", "linux")],
+    )
+    .unwrap();
+    assert!(libraries.get("testlib").is_some());
+}
+
+#[test]
+fn os_key_does_not_match() {
+    let (libraries, _flags) = toml(
+        "toml-os-linux",
+        vec![("CARGO_CFG_TARGET_OS", "macos")],
+    )
+    .unwrap();
+    assert!(libraries.get("testlib").is_none());
+}
+
+#[test]
+fn feature_version_table_selects_branch_version() {
+    let (libraries, _flags) = toml(
+        "toml-feature-versions",
+        vec![("CARGO_FEATURE_V3", "")],
+    )
+    .unwrap();
+    let testdata = libraries.get("testdata").unwrap();
+    assert_eq!(testdata.version, "3.0.0");
+}
+
+#[test]
+fn feature_version_table_optional_branch_missing_is_omitted() {
+    // Version 3 isn't available and its feature-versions branch is marked
+    // optional, so the dependency is simply left out rather than erroring.
+    let (libraries, _flags) = toml(
+        "toml-feature-versions-optional-branch",
+        vec![("CARGO_FEATURE_V3", "")],
+    )
+    .unwrap();
+    assert!(libraries.get("testdata").is_none());
+}